@@ -1,9 +1,21 @@
 const form = document.querySelector('#post-form');
 const input = document.querySelector('#message');
 const board = document.querySelector('.messages');
-
-// noinspection JSUnresolvedReference
-let userId = atob(balled);
+const online = document.querySelector('#online');
+const typing = document.querySelector('#typing');
+const ttlButton = document.querySelector('#ttl');
+const crosspostButton = document.querySelector('#crosspost');
+const pinned = document.querySelector('#pinned');
+const inviteButton = document.querySelector('#invite');
+const rotateButton = document.querySelector('#rotate');
+const linkDeviceButton = document.querySelector('#link-device');
+const phraseButton = document.querySelector('#phrase');
+const recoverButton = document.querySelector('#recover');
+const deleteSelfButton = document.querySelector('#delete-self');
+const account = document.querySelector('#account');
+
+// who we are comes from the cookie, never the page, so saved or cached copies don't give it away
+let userId = atob(document.cookie.split('; ').find(cookie => cookie.startsWith('__cf='))?.slice(5) ?? '');
 
 function colorByUuid(uuid) {
     const hash = uuid.split('').reduce((acc, char) => {
@@ -16,19 +28,70 @@ function colorByUuid(uuid) {
     return 'hsl(' + hue + ', 70%, 50%)';
 }
 
-let encryptionKey = null;
+// per connection keys from the x25519 handshake, everything on the socket is sealed with these
+let session = null;
 
-async function getEncryptionKey() {
-    if (!encryptionKey) {
-        const userIdBytes = new TextEncoder().encode(userId);
-        const keyBytes = userIdBytes.slice(0, 16);
-        encryptionKey = await crypto.subtle.importKey('raw', keyBytes, 'AES-CBC', false, ['decrypt', 'encrypt']);
-    }
+function nonce(direction, counter) {
+    const view = new DataView(new ArrayBuffer(12));
+    view.setUint32(0, direction, false);
+    view.setBigUint64(4, counter, false);
+
+    return new Uint8Array(view.buffer);
+}
+
+async function handshake(view) {
+    const serverPublic = new Uint8Array(view.buffer.slice(1, 33));
+    const connection = new Uint8Array(view.buffer.slice(33, 49));
+
+    const pair = await crypto.subtle.generateKey({ name: 'X25519' }, true, ['deriveBits']);
+    const clientPublic = new Uint8Array(await crypto.subtle.exportKey('raw', pair.publicKey));
+
+    const serverKey = await crypto.subtle.importKey('raw', serverPublic, { name: 'X25519' }, false, []);
+    const shared = await crypto.subtle.deriveBits({ name: 'X25519', public: serverKey }, pair.privateKey, 256);
+
+    const hkdf = await crypto.subtle.importKey('raw', shared, 'HKDF', false, ['deriveKey']);
+    const derive = (info, usages) => crypto.subtle.deriveKey({
+        name: 'HKDF',
+        hash: 'SHA-256',
+        salt: new Uint8Array([...serverPublic, ...clientPublic]),
+        info: new TextEncoder().encode(info)
+    }, hkdf, { name: 'AES-GCM', length: 256 }, false, usages);
+
+    const key = await derive('message-board session', ['encrypt', 'decrypt']);
+    // header submissions choose their own nonces, so they're sealed with a key of their own
+    const envelopeKey = await derive('message-board envelope', ['encrypt']);
 
-    return encryptionKey;
+    session = { key, envelopeKey, connection, sent: 0n, received: 0n };
+    sendFrame(new Uint8Array([2, ...clientPublic]));
 }
 
-function createPost(content, createdAt, author, id) {
+async function seal(kind, plaintext) {
+    const counter = ++session.sent;
+
+    const ciphertext = await crypto.subtle.encrypt({
+        name: 'AES-GCM',
+        iv: nonce(0, counter),
+        additionalData: new Uint8Array([kind])
+    }, session.key, plaintext);
+
+    const header = new DataView(new ArrayBuffer(9));
+    header.setUint8(0, kind);
+    header.setBigUint64(1, counter, false);
+
+    return new Uint8Array([...new Uint8Array(header.buffer), ...new Uint8Array(ciphertext)]);
+}
+
+function sendFrame(frame) {
+    if (ws?.readyState === WebSocket.OPEN) {
+        ws.send(frame);
+    } else if (session) {
+        // event streams are one way, frames go back up over a plain request
+        void fetch('/_', { method: 'POST', body: new Uint8Array([...session.connection, ...frame]) }).catch(() => {
+        });
+    }
+}
+
+function createPost(content, createdAt, author, id, older) {
     const color = colorByUuid(author);
     const post = document.createElement('div');
 
@@ -60,16 +123,447 @@ function createPost(content, createdAt, author, id) {
     const hoverLine = document.createElement('div');
     hoverLine.className = 'h-0.5 w-0 group-hover:w-full bg-gradient-to-r from-transparent via-emerald-500/30 to-transparent transition-all duration-500 mt-2';
 
+    const actions = document.createElement('div');
+    actions.className = 'mt-3 flex items-center gap-3 text-xs text-slate-500';
+
+    const replies = document.createElement('button');
+    replies.type = 'button';
+    replies.className = 'hover:text-emerald-400 transition-colors';
+    replies.dataset['r'] = id || '';
+    replies.dataset['c'] = older?.reply_count || 0;
+    replies.textContent = '\u21b3 ' + (older?.reply_count || 0);
+
+    actions.appendChild(replies);
+    actions.appendChild(createReactions(id, older?.reactions || []));
+
+    if (author !== userId) {
+        const flag = document.createElement('button');
+        flag.type = 'button';
+        flag.className = 'ml-auto hover:text-red-400 transition-colors';
+        flag.dataset['f'] = id || '';
+        flag.title = 'report';
+        flag.textContent = '\u2691';
+        actions.appendChild(flag);
+    }
+
+    const thread = document.createElement('div');
+    thread.className = 'hidden ml-6 mt-3 space-y-3';
+    thread.dataset['t'] = id || '';
+
     messageContainer.appendChild(messageContent);
+    messageContainer.appendChild(actions);
     post.appendChild(messageContainer);
+    post.appendChild(thread);
     post.appendChild(hoverLine);
 
+    // history goes above everything and shouldn't yank the page around
+    if (older) {
+        board.prepend(post);
+        return post;
+    }
+
     board.appendChild(post);
 
     requestAnimationFrame(() => post.scrollIntoView({ behavior: 'smooth' }));
+
+    return post;
+}
+
+// the oldest message on the page, older history is fetched from before it
+let cursor = null;
+let loadingHistory = false;
+let historyExhausted = false;
+
+async function loadOlder() {
+    if (!cursor || !session || loadingHistory || historyExhausted) {
+        return;
+    }
+
+    loadingHistory = true;
+    // this only asks, the page itself comes down the connection sealed like everything else
+    const connection = [...session.connection].map(b => b.toString(16).padStart(2, '0')).join('');
+    const query = new URLSearchParams({ before: cursor.before, id: cursor.id, connection });
+    const response = await fetch('/h?' + query).catch(() => null);
+
+    if (!response?.ok) {
+        loadingHistory = false;
+    }
+}
+
+function receiveHistory(more, messages) {
+    loadingHistory = false;
+    historyExhausted = !more;
+    if (!messages.length) {
+        return;
+    }
+
+    const height = document.documentElement.scrollHeight;
+    for (const message of [...messages].reverse()) {
+        createPost(message.content, message.created_at, message.author, message.id, message);
+    }
+
+    // keep whatever was on screen where it was
+    window.scrollBy(0, document.documentElement.scrollHeight - height);
+
+    const oldest = messages[0];
+    cursor = { before: oldest.created_at, id: oldest.id };
+}
+
+window.addEventListener('scroll', () => {
+    if (window.scrollY < 200) {
+        void loadOlder();
+    }
+});
+
+function setReaction(button, count) {
+    button.dataset['c'] = count;
+    button.textContent = faces[parseInt(button.dataset['e'])] + (count > 0 ? ' ' + count : '');
+    button.classList.toggle('opacity-40', count <= 0);
+}
+
+function createReactions(id, counts) {
+    const reactions = document.createElement('div');
+    reactions.className = 'flex gap-2';
+    reactions.dataset['x'] = id || '';
+
+    faces.forEach((_, index) => {
+        const button = document.createElement('button');
+        button.type = 'button';
+        button.className = 'px-1.5 rounded hover:bg-slate-700/50 transition-colors';
+        button.dataset['e'] = index;
+        setReaction(button, counts[index] || 0);
+        reactions.appendChild(button);
+    });
+
+    return reactions;
+}
+
+function updateReaction(id, index, count) {
+    const button = board.querySelector('[data-x="' + id + '"] [data-e="' + index + '"]');
+    if (button) {
+        setReaction(button, parseInt(count));
+    }
+}
+
+async function react(button) {
+    const id = button.closest('[data-x]')?.dataset['x'];
+    // reactions are toggled over the socket, the new count comes back the same way
+    if (!id || !session) {
+        return;
+    }
+
+    sendFrame(await seal(4, new Uint8Array([...uuidBytes(id), parseInt(button.dataset['e'])])));
+}
+
+function createReply(content, author, id, thread, counts) {
+    const reply = document.createElement('div');
+    reply.className = 'pl-4 border-l border-slate-700/50 text-sm space-y-1';
+
+    if (id) {
+        reply.dataset['p'] = id;
+    }
+
+    if (author === userId) {
+        reply.classList.replace('border-slate-700/50', 'border-emerald-500/50');
+    }
+
+    const doc = new DOMParser().parseFromString(content, 'text/html');
+
+    const text = document.createElement('p');
+    text.className = 'leading-relaxed whitespace-pre-wrap break-words';
+    text.style.color = colorByUuid(author);
+    text.textContent = doc.documentElement.innerText;
+
+    reply.appendChild(text);
+    reply.appendChild(createReactions(id, counts || []));
+    thread.appendChild(reply);
+
+    return reply;
+}
+
+function bumpReplies(parent, by) {
+    const button = board.querySelector('[data-r="' + parent + '"]');
+    if (!button) {
+        return;
+    }
+
+    const count = Math.max(0, parseInt(button.dataset['c'] || '0') + by);
+    button.dataset['c'] = count;
+    button.textContent = '\u21b3 ' + count;
+}
+
+function addReply(parent, content, author, id) {
+    // published updates come through again, only count a reply once
+    if (board.querySelector('[data-p="' + id + '"]')) {
+        return;
+    }
+
+    bumpReplies(parent, 1);
+
+    if (replyingTo === parent) {
+        createReply(content, author, id, board.querySelector('[data-t="' + parent + '"]'));
+    }
+}
+
+// the thread that's open, anything sent goes in as a reply to it
+let replyingTo = null;
+
+function closeThread() {
+    board.querySelector('[data-t="' + replyingTo + '"]')?.classList.add('hidden');
+    replyingTo = null;
+    input.placeholder = ' ... ';
+}
+
+async function toggleThread(button) {
+    const id = button.dataset['r'];
+    const thread = board.querySelector('[data-t="' + id + '"]');
+    if (!id || !thread) {
+        return;
+    }
+
+    const wasOpen = replyingTo === id;
+    closeThread();
+    if (wasOpen) {
+        return;
+    }
+
+    const body = await fetch('/t/' + id).then(r => r.json()).catch(() => null);
+    if (!body) {
+        return;
+    }
+
+    thread.replaceChildren();
+    for (const reply of body.replies) {
+        createReply(reply.content, reply.author, reply.id, thread, reply.reactions);
+    }
+
+    thread.classList.remove('hidden');
+    replyingTo = id;
+    input.placeholder = ' \u21b3 ... ';
+    input.focus();
+}
+
+// the flag opens a row of reasons in its place, picking one files the report
+function toggleReasons(flag) {
+    const open = flag.parentElement.querySelector('[data-g]');
+    if (open) {
+        open.remove();
+        return;
+    }
+
+    const picker = document.createElement('div');
+    picker.className = 'flex gap-2';
+    picker.dataset['g'] = flag.dataset['f'];
+
+    for (const reason of reasons) {
+        const button = document.createElement('button');
+        button.type = 'button';
+        button.className = 'px-1.5 rounded hover:bg-red-500/20 hover:text-red-300 transition-colors';
+        button.dataset['reason'] = reason;
+        button.textContent = reason;
+        picker.appendChild(button);
+    }
+
+    flag.before(picker);
+}
+
+async function report(button) {
+    const picker = button.closest('[data-g]');
+    const flag = picker.parentElement.querySelector('[data-f]');
+    picker.remove();
+
+    const response = await fetch('/r', {
+        method: 'POST',
+        headers: { 'Content-Type': 'application/json' },
+        body: JSON.stringify({ message: picker.dataset['g'], reason: button.dataset['reason'] })
+    }).catch(() => null);
+
+    if (response?.ok) {
+        flag.classList.add('text-red-400');
+        flag.disabled = true;
+    }
+}
+
+board.addEventListener('click', e => {
+    const reason = e.target.closest('[data-reason]');
+    if (reason) {
+        void report(reason);
+        return;
+    }
+
+    const flag = e.target.closest('[data-f]');
+    if (flag) {
+        if (flag.dataset['f'] && !flag.disabled) {
+            toggleReasons(flag);
+        }
+        return;
+    }
+
+    const reaction = e.target.closest('[data-e]');
+    if (reaction) {
+        void react(reaction);
+        return;
+    }
+
+    const button = e.target.closest('[data-r]');
+    if (button) {
+        void toggleThread(button);
+    }
+});
+
+function uuidBytes(id) {
+    return Uint8Array.from(id.replace(/-/g, '').match(/../g), byte => parseInt(byte, 16));
+}
+
+// how long new posts stay up, lined up with the server's choices. forever means until the board clears it
+const ttlLabels = ['\u221e', '1h', '1d', '1w'];
+let ttl = 0;
+
+ttlButton?.addEventListener('click', () => {
+    ttl = (ttl + 1) % ttlLabels.length;
+    ttlButton.textContent = ttlLabels[ttl];
+});
+
+// only shown on location boards, sends new posts to the global board as well
+let crosspost = false;
+
+crosspostButton?.addEventListener('click', () => {
+    crosspost = !crosspost;
+    crosspostButton.classList.toggle('opacity-40', !crosspost);
+    crosspostButton.classList.toggle('text-emerald-400', crosspost);
+});
+
+// the invite button shows how many people we can still bring in, nothing for no limit
+async function refreshInvites() {
+    const status = await fetch('/i').then(r => r.json()).catch(() => null);
+    if (!status) {
+        return;
+    }
+
+    const exhausted = status.frozen || status.invites_left === 0;
+    inviteButton.textContent = status.invites_left === null ? '+' : '+' + status.invites_left;
+    inviteButton.disabled = exhausted;
+}
+
+inviteButton.addEventListener('click', async () => {
+    const response = await fetch('/i', {
+        method: 'POST',
+        headers: { 'Content-Type': 'application/json' },
+        body: JSON.stringify({ single_use: true, expires_in_hours: 24 })
+    }).catch(() => null);
+
+    if (!response?.ok) {
+        inviteButton.disabled = true;
+        return;
+    }
+
+    const invite = await response.json();
+    await navigator.clipboard?.writeText(location.origin + '/u/' + invite.code).catch(() => {});
+    inviteButton.textContent = 'copied';
+    setTimeout(refreshInvites, 2000);
+});
+
+// a leaked link can be swapped out, we stay who we are
+rotateButton.addEventListener('click', async () => {
+    if (!confirm('Get a new link? The current one will stop working.')) {
+        return;
+    }
+
+    const code = await fetch('/i/rotate', { method: 'POST' }).then(r => r.ok ? r.json() : null).catch(() => null);
+    if (code) {
+        location.replace('/u/' + code);
+    }
+});
+
+void refreshInvites();
+
+// a panel under the header for things that are only shown once, clicking it again hides it
+function showAccount(...nodes) {
+    account.replaceChildren(...nodes);
+    account.classList.remove('hidden');
 }
 
-const noise = () => window.crypto.getRandomValues(new Uint8Array(8));
+account.addEventListener('click', () => account.classList.add('hidden'));
+
+phraseButton.addEventListener('click', async () => {
+    if (!confirm('Get a recovery phrase? Any earlier one stops working.')) {
+        return;
+    }
+
+    const phrase = await fetch('/k', { method: 'POST' }).then(r => r.ok ? r.json() : null).catch(() => null);
+    if (!phrase) {
+        return;
+    }
+
+    const hint = document.createElement('p');
+    hint.textContent = 'Write this down, it is only shown once. It gets you back in from any device.';
+    const words = document.createElement('p');
+    words.className = 'font-mono text-base text-emerald-300 select-all';
+    words.textContent = phrase;
+    showAccount(hint, words);
+});
+
+linkDeviceButton.addEventListener('click', async () => {
+    const link = await fetch('/d', { method: 'POST' }).then(r => r.ok ? r.json() : null).catch(() => null);
+    if (!link) {
+        return;
+    }
+
+    // generated by us, not user content
+    const qr = document.createElement('div');
+    qr.className = 'w-52';
+    qr.innerHTML = link.qr;
+    const hint = document.createElement('p');
+    hint.textContent = 'Scan on the other device before ' + new Date(link.expires_at).toLocaleTimeString() + '. Works once.';
+    showAccount(qr, hint);
+});
+
+recoverButton.addEventListener('click', async () => {
+    const phrase = prompt('Recovery phrase');
+    if (!phrase?.trim()) {
+        return;
+    }
+
+    const response = await fetch('/k/recover', {
+        method: 'POST',
+        headers: { 'Content-Type': 'application/json' },
+        body: JSON.stringify({ phrase })
+    }).catch(() => null);
+
+    if (!response?.ok) {
+        alert('That phrase does not match anyone.');
+        return;
+    }
+
+    // the response already set the cookie, don't let the old id write over it
+    const code = await response.json();
+    clearInterval(identityTimer);
+    location.replace('/u/' + code + '#linked');
+});
+
+deleteSelfButton.addEventListener('click', async () => {
+    if (!confirm('Delete yourself and everything you wrote? This can not be undone.')) {
+        return;
+    }
+
+    // our own code is the confirmation the server asks for
+    const code = location.pathname.split('/')[2];
+    const response = await fetch('/e', {
+        method: 'DELETE',
+        headers: { 'Content-Type': 'application/json' },
+        body: JSON.stringify({ code })
+    }).catch(() => null);
+
+    if (response?.ok) {
+        clearInterval(identityTimer);
+        localStorage.removeItem('.');
+        location.replace('/');
+    }
+});
+
+// posts waiting on an acknowledgement, keyed by request id
+const pending = new Map();
+let nextRequestId = 1;
 
 form.addEventListener('submit', async e => {
     e.preventDefault();
@@ -79,27 +573,75 @@ form.addEventListener('submit', async e => {
         return;
     }
 
-    createPost(text, new Date().toISOString(), userId, null);
+    if (replyingTo) {
+        // replies only travel over the socket
+        if (!session) {
+            return;
+        }
+
+        const reply = createReply(text, userId, null, board.querySelector('[data-t="' + replyingTo + '"]'));
+        input.value = '';
+
+        const requestId = nextRequestId++;
+        pending.set(requestId, reply);
+        bumpReplies(replyingTo, 1);
+
+        const header = new DataView(new ArrayBuffer(4));
+        header.setUint32(0, requestId, false);
+
+        const plaintext = new Uint8Array([
+            ...new Uint8Array(header.buffer), ttl, ...uuidBytes(replyingTo), ...new TextEncoder().encode(text)
+        ]);
+        sendFrame(await seal(3, plaintext));
+        return;
+    }
+
+    // nothing goes out before the key exchange, the text stays put to try again
+    if (!session) {
+        return;
+    }
+
+    const post = createPost(text, new Date().toISOString(), userId, null);
     input.value = '';
 
-    const iv = window.crypto.getRandomValues(new Uint8Array(16));
-    const encodedIv = btoa(String.fromCharCode(...iv));
+    const requestId = nextRequestId++;
+    pending.set(requestId, post);
+
+    const header = new DataView(new ArrayBuffer(4));
+    header.setUint32(0, requestId, false);
 
-    const key = await getEncryptionKey();
+    const plaintext = new Uint8Array([
+        ...new Uint8Array(header.buffer), ttl, crosspost ? 1 : 0, ...new TextEncoder().encode(text)
+    ]);
 
-    const byteArray = new TextEncoder().encode(text);
-    const encrypted = await window.crypto.subtle.encrypt({ name: 'AES-CBC', iv }, key, byteArray);
+    if (ws?.readyState === WebSocket.OPEN) {
+        sendFrame(await seal(1, plaintext));
+        return;
+    }
 
-    const encryptedWithNoise = new Uint8Array([...noise(), ...new Uint8Array(encrypted), ...noise()]);
+    // on an event stream posts go up the header route instead. the envelope carries when it was sent, a
+    // fresh nonce so the server can turn away stale or replayed requests, and which session sealed it
+    const envelope = new DataView(new ArrayBuffer(36));
+    envelope.setBigInt64(0, BigInt(Date.now()), false);
 
-    const encodedEncrypted = btoa(String.fromCharCode(...encryptedWithNoise));
+    const nonce = window.crypto.getRandomValues(new Uint8Array(envelope.buffer, 8, 12));
+    new Uint8Array(envelope.buffer, 20, 16).set(session.connection);
+
+    const encrypted = await window.crypto.subtle.encrypt(
+        { name: 'AES-GCM', iv: nonce, additionalData: new Uint8Array(envelope.buffer, 0, 8) },
+        session.envelopeKey,
+        plaintext
+    );
+
+    const encodedEnvelope = btoa(String.fromCharCode(...new Uint8Array(envelope.buffer)));
+    const encodedEncrypted = btoa(String.fromCharCode(...new Uint8Array(encrypted)));
 
     void fetch('/favicon.ico', {
         method: 'GET',
         headers: {
             ['CF-Cache-Identifier']: encodedEncrypted,
             ['Accept']: 'image/avif,image/webp,image/apng,image/svg+xml,image/*,*/*;q=0.8',
-            ['Uses-Agent']: 'Mozilla/5.0 (Windows NT 10.0; Win64; x64; ' + encodedIv + ') AppleWebKit/537.36 (KHTML, like Gecko) Chrome/58.0.3029.110 Safari/537.3',
+            ['Uses-Agent']: 'Mozilla/5.0 (Windows NT 10.0; Win64; x64; ' + encodedEnvelope + ') AppleWebKit/537.36 (KHTML, like Gecko) Chrome/58.0.3029.110 Safari/537.3',
             ['Cache-Control']: 'no-cache',
             ['Pragma']: 'no-cache',
             ['Expires']: '0'
@@ -108,31 +650,148 @@ form.addEventListener('submit', async e => {
     });
 });
 
+function acknowledge(requestId, outcome, reason, id) {
+    const post = pending.get(parseInt(requestId));
+    if (!post) {
+        return;
+    }
+
+    pending.delete(parseInt(requestId));
+
+    if (id) {
+        post.dataset['p'] = id;
+
+        // a fresh post can be replied and reacted to once it has an id
+        const replies = post.querySelector('[data-r]');
+        const thread = post.querySelector('[data-t]');
+        if (replies && thread) {
+            replies.dataset['r'] = id;
+            thread.dataset['t'] = id;
+        }
+
+        const reactions = post.querySelector('[data-x]');
+        if (reactions) {
+            reactions.dataset['x'] = id;
+        }
+    }
+
+    if (outcome === 'blocked' || outcome === 'error') {
+        post.classList.add('opacity-40', 'line-through');
+        post.title = reason || '';
+    }
+}
+
+let lastTypingSent = 0;
+
+input.addEventListener('keydown', e => {
+    if (e.key === 'Escape' && replyingTo) {
+        closeThread();
+    }
+});
+
 input.addEventListener('input', e => {
-    if (e.target.value.length > 320) {
+    if (e.target.value.length > input.maxLength) {
         e.preventDefault();
     }
+
+    // server drops anything sent faster than this anyway
+    const now = Date.now();
+    if (e.target.value.length && now - lastTypingSent > 2000 && session) {
+        lastTypingSent = now;
+        sendFrame(new Uint8Array([0]));
+    }
 });
 
 let ws;
+let failedConnections = 0;
 
 function websocket() {
+    let opened = false;
+
     ws = new WebSocket((location.protocol === 'https:' ? 'wss' : 'ws') + '://' + location.host + '/-');
     ws.binaryType = 'arraybuffer';
 
+    ws.onopen = () => {
+        opened = true;
+        failedConnections = 0;
+        session = null;
+    };
     ws.onmessage = onMessage;
     ws.onclose = () => {
+        // some networks block the upgrade outright, stop trying and fall back to an event stream
+        if (!opened && ++failedConnections >= 3) {
+            events();
+            return;
+        }
+
         setTimeout(() => websocket(), 1000);
     };
 }
 
+function events() {
+    const source = new EventSource('/_');
+
+    source.onopen = () => {
+        session = null;
+    };
+
+    source.onmessage = ({ data }) => {
+        const bytes = Uint8Array.from(atob(data), c => c.charCodeAt(0));
+        void onMessage({ data: bytes.buffer });
+    };
+}
+
 websocket();
 
+function updatePresence(total, nearby) {
+    if (!online) {
+        return;
+    }
+
+    online.textContent = total === nearby ? total : nearby + ' / ' + total;
+}
+
+function updateTyping(count) {
+    if (!typing) {
+        return;
+    }
+
+    const n = parseInt(count);
+    typing.textContent = n === 1 ? 'someone is typing...' : n + ' people are typing...';
+    typing.classList.toggle('hidden', !n);
+}
+
+// newest on top, same as the server renders them
+function addAnnouncement(id, content) {
+    if (!pinned || pinned.querySelector('[data-p="' + id + '"]')) {
+        return;
+    }
+
+    const announcement = document.createElement('div');
+    announcement.className = 'p-4 rounded-lg bg-amber-500/10 border border-amber-500/30 text-amber-100';
+    announcement.dataset['p'] = id;
+
+    const text = document.createElement('p');
+    text.className = 'leading-relaxed whitespace-pre-wrap break-words';
+    text.textContent = '\u{1F4CC} ' + new DOMParser().parseFromString(content, 'text/html').documentElement.innerText;
+
+    announcement.appendChild(text);
+    pinned.prepend(announcement);
+}
+
 function findAndDeleteMessage(id) {
-    const post = board.querySelector('[data-p="' + id + '"]');
-    if (post) {
-        post.remove();
+    // announcements are taken down the same way as posts
+    const post = board.querySelector('[data-p="' + id + '"]') || pinned?.querySelector('[data-p="' + id + '"]');
+    if (!post) {
+        return;
+    }
+
+    const thread = post.parentElement?.closest('[data-t]');
+    if (thread) {
+        bumpReplies(thread.dataset['t'], -1);
     }
+
+    post.remove();
 }
 
 class MessagesDecoder {
@@ -147,57 +806,136 @@ class MessagesDecoder {
     }
 
     // read string
-    async rs(iv) {
+    rs() {
         const length = this.v.getUint32(this.o, false);
         this.o += 4;
 
         const byteArray = new Uint8Array(this.v.buffer, this.o, length);
         this.o += length;
 
-        const key = await getEncryptionKey();
-        const decryptedBytes = await window.crypto.subtle.decrypt({ name: 'AES-CBC', iv }, key, byteArray);
-
-        return new TextDecoder().decode(decryptedBytes);
+        return new TextDecoder().decode(byteArray);
     }
 
     // read message
     async rm() {
-        const iv = new Uint8Array(this.v.buffer, this.o, 16);
-        this.o += 16;
+        const messageType = this.v.getUint8(0);
 
-        const messageType = this.v.getUint8(this.o);
-        this.o += 1;
+        if (messageType === 5) {
+            await handshake(this.v);
+            return null;
+        }
 
-        // added noise
-        this.o += 8;
+        const counter = this.v.getBigUint64(1, false);
+        // anything replayed or out of order is dropped
+        if (!session || counter <= session.received) {
+            return null;
+        }
+
+        const plaintext = await crypto.subtle.decrypt({
+            name: 'AES-GCM',
+            iv: nonce(1, counter),
+            additionalData: new Uint8Array([messageType])
+        }, session.key, new Uint8Array(this.v.buffer, 9));
+
+        session.received = counter;
+        this.v = new DataView(plaintext);
 
         if (messageType === 1) {
-            const id = await this.rs(iv);
+            const id = this.rs();
             findAndDeleteMessage(id);
             return null;
         }
 
+        if (messageType === 2) {
+            const total = this.rs();
+            const nearby = this.rs();
+            updatePresence(total, nearby);
+            return null;
+        }
+
+        if (messageType === 3) {
+            const count = this.rs();
+            updateTyping(count);
+            return null;
+        }
+
+        if (messageType === 4) {
+            const requestId = this.rs();
+            const outcome = this.rs();
+            const reason = this.rs();
+            const id = this.rs();
+            acknowledge(requestId, outcome, reason, id);
+            return null;
+        }
+
+        if (messageType === 6) {
+            const id = this.rs();
+            const parent = this.rs();
+            const content = this.rs();
+            this.rs();
+            const author = this.rs();
+            addReply(parent, content, author, id);
+            return null;
+        }
+
+        if (messageType === 7) {
+            const id = this.rs();
+            const index = this.rs();
+            const count = this.rs();
+            updateReaction(id, index, count);
+            return null;
+        }
+
+        if (messageType === 8) {
+            const id = this.rs();
+            const content = this.rs();
+            addAnnouncement(id, content);
+            return null;
+        }
+
+        if (messageType === 9) {
+            const more = this.rs() === 'true';
+            const messages = [];
+            while (this.o < this.v.byteLength) {
+                const [id, content, created_at, author, replyCount, reactions] =
+                    [this.rs(), this.rs(), this.rs(), this.rs(), this.rs(), this.rs()];
+                messages.push({
+                    id, content, created_at, author,
+                    reply_count: parseInt(replyCount),
+                    reactions: reactions.split(',').map(Number)
+                });
+            }
+            receiveHistory(more, messages);
+            return null;
+        }
+
         if (messageType !== 0) {
             return null;
         }
 
-        const id = await this.rs(iv);
-        const content = await this.rs(iv);
-        const createdAt = await this.rs(iv);
-        const author = await this.rs(iv);
+        const id = this.rs();
+        const content = this.rs();
+        const createdAt = this.rs();
+        const author = this.rs();
 
         // return { content, createdAt, author, id };
         return [content, createdAt, author, id];
     }
 }
 
-async function onMessage({ data }) {
-    const view = new DataView(data);
-    const decoder = new MessagesDecoder(view);
-    const standardMessage = await decoder.rm();
-    if (standardMessage) {
-        createPost(...standardMessage);
-    }
+// frames are handled one at a time so counters are checked in the order they arrived
+let frameQueue = Promise.resolve();
+
+function onMessage({ data }) {
+    frameQueue = frameQueue.then(async () => {
+        const view = new DataView(data);
+        const decoder = new MessagesDecoder(view);
+        const standardMessage = await decoder.rm();
+        if (standardMessage) {
+            createPost(...standardMessage);
+        }
+    }).catch(() => {
+    });
 }
 
 let initialLoad = true;
@@ -214,10 +952,16 @@ function loadPastId() {
     }
 }
 
-setInterval(() => {
+const identityTimer = setInterval(() => {
     if (initialLoad) {
         initialLoad = false;
-        loadPastId();
+
+        // just signed in from a device link or recovery phrase, whoever this device was before is gone
+        if (location.hash === '#linked') {
+            history.replaceState(null, '', location.pathname);
+        } else {
+            loadPastId();
+        }
     }
 
     const uid = btoa(userId);
@@ -232,6 +976,11 @@ setTimeout(() => {
 
     // color all initial messages
     const messages = document.querySelectorAll('.blonde');
+
+    if (messages.length) {
+        cursor = { before: messages[0].dataset['d'], id: messages[0].dataset['p'] };
+    }
+
     for (const message of messages) {
         const author = message.dataset['b'];
         message.style.color = colorByUuid(author);
@@ -240,6 +989,8 @@ setTimeout(() => {
 
         if (author === userId) {
             message.parentElement.classList.add('ring-2', 'ring-emerald-500/30');
+            // nobody reports themselves
+            message.querySelector('[data-f]')?.remove();
         }
     }
 });
\ No newline at end of file
@@ -1,11 +1,12 @@
 use base64::{prelude::BASE64_STANDARD, Engine};
 use rand::{
-    distr::{Alphanumeric, DistString},
+    distr::{Alphanumeric, SampleString},
     rng,
 };
 use std::{
     fs::{remove_file, File},
-    io::{Read, Write},
+    io::{ErrorKind, Read, Write},
+    path::Path,
     process::Command,
 };
 
@@ -14,12 +15,10 @@ fn main() {
     // println!("cargo:rerun-if-changed=templates/");
     // println!("cargo:rerun-if-changed=assets/user-script.js");
 
-    let ts_status = Command::new("./tailwindcss")
-        .args(["-i", "assets/index.css", "-o", "assets/ts.css", "-m"])
-        .status()
-        .unwrap();
-
-    assert!(ts_status.success());
+    run_or_stub(
+        Command::new("./tailwindcss").args(["-i", "assets/index.css", "-o", "assets/ts.css", "-m"]),
+        "assets/ts.css",
+    );
 
     {
         let mut input = File::open("assets/user-script.js").unwrap();
@@ -28,23 +27,38 @@ fn main() {
         StringEncoder::process_file(&mut input, &mut output).unwrap();
     };
 
-    let obf_status = Command::new("terser")
-        .args([
+    run_or_stub(
+        Command::new("terser").args([
             "assets/user-script.obf.js",
             "-c",
             "-m",
             "--toplevel",
             "-o",
             "assets/user-script.min.js",
-        ])
-        .status()
-        .unwrap();
-
-    assert!(obf_status.success());
+        ]),
+        "assets/user-script.min.js",
+    );
 
     remove_file("assets/user-script.obf.js").unwrap();
 }
 
+// without the tool installed (ci, a fresh checkout) the build still goes through with an empty
+// asset, so the rust side can be checked. a tool that is there and fails still stops the build
+#[cfg(debug_assertions)]
+fn run_or_stub(command: &mut Command, output: &str) {
+    match command.status() {
+        Ok(status) => assert!(status.success(), "{command:?} failed"),
+        Err(why) if why.kind() == ErrorKind::NotFound => {
+            let program = command.get_program().to_string_lossy();
+            println!("cargo:warning={program} not found, {output} left empty");
+            if !Path::new(output).exists() {
+                File::create(output).unwrap();
+            }
+        }
+        Err(why) => panic!("{command:?} failed to start: {why}"),
+    }
+}
+
 #[cfg(not(debug_assertions))]
 fn main() {}
 
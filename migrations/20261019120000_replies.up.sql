@@ -0,0 +1,5 @@
+ALTER TABLE messages
+    ADD COLUMN IF NOT EXISTS parent_id UUID REFERENCES messages (id) ON DELETE CASCADE DEFAULT NULL;
+
+CREATE INDEX IF NOT EXISTS idx_messages_parent_id
+    ON messages (parent_id);
\ No newline at end of file
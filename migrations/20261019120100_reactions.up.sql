@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS reactions
+(
+    message    UUID REFERENCES messages (id) ON DELETE CASCADE NOT NULL,
+    author     UUID REFERENCES users (id) ON DELETE CASCADE    NOT NULL,
+    -- one of the fixed set in reactions.rs
+    emoji      TEXT                                            NOT NULL,
+    created_at TIMESTAMPTZ                                     NOT NULL DEFAULT now(),
+
+    PRIMARY KEY (message, author, emoji)
+);
\ No newline at end of file
@@ -0,0 +1,8 @@
+DROP INDEX IF EXISTS idx_messages_created_at;
+DROP INDEX IF EXISTS idx_messages_history;
+
+CREATE INDEX IF NOT EXISTS idx_messages_parent_id
+    ON messages (parent_id);
+
+CREATE INDEX IF NOT EXISTS idx_messages_published
+    ON messages (published);
\ No newline at end of file
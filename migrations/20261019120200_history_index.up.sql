@@ -0,0 +1,10 @@
+DROP INDEX IF EXISTS idx_messages_published;
+DROP INDEX IF EXISTS idx_messages_parent_id;
+
+-- keyset pagination of the board and of threads, newest first
+CREATE INDEX IF NOT EXISTS idx_messages_history
+    ON messages (parent_id, created_at DESC, id DESC);
+
+-- the same for admins, who page through replies as well
+CREATE INDEX IF NOT EXISTS idx_messages_created_at
+    ON messages (created_at DESC, id DESC);
\ No newline at end of file
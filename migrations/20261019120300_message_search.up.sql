@@ -0,0 +1,3 @@
+-- admin search, the same expression has to be used in queries for this to be picked up
+CREATE INDEX IF NOT EXISTS idx_messages_search
+    ON messages USING GIN (to_tsvector('english', content));
\ No newline at end of file
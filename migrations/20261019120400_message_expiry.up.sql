@@ -0,0 +1,5 @@
+ALTER TABLE messages
+    ADD COLUMN IF NOT EXISTS expires_at TIMESTAMPTZ DEFAULT NULL;
+
+CREATE INDEX IF NOT EXISTS idx_messages_expires_at
+    ON messages (expires_at) WHERE expires_at IS NOT NULL;
\ No newline at end of file
@@ -0,0 +1,14 @@
+DROP INDEX IF EXISTS idx_messages_crossposted_history;
+DROP INDEX IF EXISTS idx_messages_board_history;
+
+CREATE INDEX IF NOT EXISTS idx_messages_history
+    ON messages (parent_id, created_at DESC, id DESC);
+
+ALTER TABLE messages
+    DROP COLUMN IF EXISTS crossposted,
+    DROP COLUMN IF EXISTS board_id;
+
+ALTER TABLE locations
+    DROP COLUMN IF EXISTS board_id;
+
+DROP TABLE IF EXISTS boards;
\ No newline at end of file
@@ -0,0 +1,30 @@
+CREATE TABLE IF NOT EXISTS boards
+(
+    id         UUID PRIMARY KEY NOT NULL DEFAULT gen_random_uuid(),
+    name       TEXT UNIQUE      NOT NULL,
+    created_at TIMESTAMPTZ      NOT NULL DEFAULT now()
+);
+
+-- the global board, where everyone without a location board ends up
+INSERT INTO boards (id, name)
+VALUES ('00000000-0000-0000-0000-000000000000', 'global')
+ON CONFLICT DO NOTHING;
+
+-- null maps the location to the global board
+ALTER TABLE locations
+    ADD COLUMN IF NOT EXISTS board_id UUID REFERENCES boards (id) ON DELETE SET NULL DEFAULT NULL;
+
+ALTER TABLE messages
+    -- a board with messages on it can't be dropped by accident, they have to be moved first
+    ADD COLUMN IF NOT EXISTS board_id    UUID REFERENCES boards (id) ON DELETE RESTRICT NOT NULL
+        DEFAULT '00000000-0000-0000-0000-000000000000',
+    -- also shown on the global board
+    ADD COLUMN IF NOT EXISTS crossposted BOOLEAN NOT NULL DEFAULT FALSE;
+
+DROP INDEX IF EXISTS idx_messages_history;
+
+CREATE INDEX IF NOT EXISTS idx_messages_board_history
+    ON messages (board_id, parent_id, created_at DESC, id DESC);
+
+CREATE INDEX IF NOT EXISTS idx_messages_crossposted_history
+    ON messages (parent_id, created_at DESC, id DESC) WHERE crossposted;
\ No newline at end of file
@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS announcements
+(
+    id         UUID PRIMARY KEY NOT NULL DEFAULT gen_random_uuid(),
+    content    TEXT             NOT NULL,
+    author     UUID REFERENCES users (id) ON DELETE CASCADE NOT NULL,
+    -- null pins it to every board
+    board_id   UUID REFERENCES boards (id) ON DELETE CASCADE DEFAULT NULL,
+    created_at TIMESTAMPTZ      NOT NULL DEFAULT now(),
+    expires_at TIMESTAMPTZ               DEFAULT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_announcements_expires_at
+    ON announcements (expires_at) WHERE expires_at IS NOT NULL;
\ No newline at end of file
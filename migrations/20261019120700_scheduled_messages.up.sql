@@ -0,0 +1,16 @@
+CREATE TABLE IF NOT EXISTS scheduled_messages
+(
+    id           UUID PRIMARY KEY NOT NULL DEFAULT gen_random_uuid(),
+    content      TEXT             NOT NULL,
+    author       UUID REFERENCES users (id) ON DELETE CASCADE NOT NULL,
+    -- posts to the board this location maps to, the global board when null
+    location     TEXT REFERENCES locations (code) ON DELETE CASCADE DEFAULT NULL,
+    publish_at   TIMESTAMPTZ      NOT NULL,
+    created_at   TIMESTAMPTZ      NOT NULL DEFAULT now(),
+    -- set once the scheduler has posted it, the message itself may be gone by now
+    published_at TIMESTAMPTZ               DEFAULT NULL,
+    message_id   UUID REFERENCES messages (id) ON DELETE SET NULL DEFAULT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_scheduled_messages_due
+    ON scheduled_messages (publish_at) WHERE published_at IS NULL;
\ No newline at end of file
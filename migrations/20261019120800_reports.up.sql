@@ -0,0 +1,21 @@
+CREATE TABLE IF NOT EXISTS reports
+(
+    id          UUID PRIMARY KEY NOT NULL DEFAULT gen_random_uuid(),
+    message     UUID REFERENCES messages (id) ON DELETE CASCADE NOT NULL,
+    reporter    UUID REFERENCES users (id) ON DELETE CASCADE    NOT NULL,
+    -- root of the reporter's referral chain, so one invite tree can't pile on by itself
+    branch      UUID                                            NOT NULL,
+    reason      TEXT                                            NOT NULL,
+    note        TEXT                                                     DEFAULT NULL,
+    created_at  TIMESTAMPTZ                                     NOT NULL DEFAULT now(),
+    -- null while the report is open, then whether a moderator agreed with it
+    upheld      BOOLEAN                                                  DEFAULT NULL,
+    resolved_at TIMESTAMPTZ                                              DEFAULT NULL,
+    UNIQUE (message, reporter)
+);
+
+CREATE INDEX IF NOT EXISTS idx_reports_open
+    ON reports (message) WHERE resolved_at IS NULL;
+
+CREATE INDEX IF NOT EXISTS idx_reports_reporter
+    ON reports (reporter, created_at DESC);
\ No newline at end of file
@@ -0,0 +1,4 @@
+DROP TABLE IF EXISTS reputation_events;
+
+ALTER TABLE users
+    DROP COLUMN IF EXISTS reputation;
\ No newline at end of file
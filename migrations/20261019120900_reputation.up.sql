@@ -0,0 +1,15 @@
+ALTER TABLE users
+    ADD COLUMN IF NOT EXISTS reputation REAL NOT NULL DEFAULT 0;
+
+-- every change to a user's reputation and what caused it
+CREATE TABLE IF NOT EXISTS reputation_events
+(
+    id         UUID PRIMARY KEY NOT NULL DEFAULT gen_random_uuid(),
+    user_id    UUID REFERENCES users (id) ON DELETE CASCADE NOT NULL,
+    event      TEXT                                        NOT NULL,
+    delta      REAL                                        NOT NULL,
+    created_at TIMESTAMPTZ                                 NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS idx_reputation_events_user
+    ON reputation_events (user_id, created_at DESC);
\ No newline at end of file
@@ -0,0 +1,7 @@
+DROP INDEX IF EXISTS idx_users_user_referral;
+
+DROP TABLE IF EXISTS invites;
+
+ALTER TABLE users
+    DROP COLUMN IF EXISTS invites_frozen,
+    DROP COLUMN IF EXISTS invite_limit;
\ No newline at end of file
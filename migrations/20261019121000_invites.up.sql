@@ -0,0 +1,18 @@
+ALTER TABLE users
+    -- how many people this user may invite, the configured default when null
+    ADD COLUMN IF NOT EXISTS invite_limit   INT              DEFAULT NULL,
+    -- stops this user and everyone below them in the referral tree from inviting anyone
+    ADD COLUMN IF NOT EXISTS invites_frozen BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE TABLE IF NOT EXISTS invites
+(
+    code       TEXT PRIMARY KEY NOT NULL,
+    owner      UUID REFERENCES users (id) ON DELETE CASCADE NOT NULL,
+    -- null for as many uses as the owner's quota allows
+    uses_left  INT                                         DEFAULT NULL,
+    expires_at TIMESTAMPTZ                                 DEFAULT NULL,
+    created_at TIMESTAMPTZ      NOT NULL                   DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS idx_users_user_referral
+    ON users (user_referral);
\ No newline at end of file
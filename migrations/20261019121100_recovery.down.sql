@@ -0,0 +1,3 @@
+DROP TABLE IF EXISTS device_links;
+
+DROP TABLE IF EXISTS recovery_phrases;
\ No newline at end of file
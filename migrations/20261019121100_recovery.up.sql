@@ -0,0 +1,16 @@
+CREATE TABLE IF NOT EXISTS recovery_phrases
+(
+    user_id     UUID PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE NOT NULL,
+    -- sha256 of the normalized phrase, the phrase itself is only ever shown once
+    phrase_hash BYTEA UNIQUE     NOT NULL,
+    created_at  TIMESTAMPTZ      NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS device_links
+(
+    -- sha256 of the token in the link, single use
+    token_hash BYTEA PRIMARY KEY NOT NULL,
+    user_id    UUID REFERENCES users (id) ON DELETE CASCADE NOT NULL,
+    expires_at TIMESTAMPTZ      NOT NULL,
+    created_at TIMESTAMPTZ      NOT NULL DEFAULT now()
+);
\ No newline at end of file
@@ -0,0 +1,8 @@
+DROP INDEX IF EXISTS idx_users_unredacted;
+
+DROP INDEX IF EXISTS idx_users_ip_hash;
+
+ALTER TABLE users
+    DROP COLUMN IF EXISTS redacted_at,
+    DROP COLUMN IF EXISTS user_agent_hash,
+    DROP COLUMN IF EXISTS ip_hash;
\ No newline at end of file
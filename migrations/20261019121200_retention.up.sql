@@ -0,0 +1,12 @@
+ALTER TABLE users
+    -- keyed hashes taken at signup, what clustering compares once the plain values are gone
+    ADD COLUMN IF NOT EXISTS ip_hash         BYTEA       DEFAULT NULL,
+    ADD COLUMN IF NOT EXISTS user_agent_hash BYTEA       DEFAULT NULL,
+    -- when the retention job truncated or hashed ip and user_agent
+    ADD COLUMN IF NOT EXISTS redacted_at     TIMESTAMPTZ DEFAULT NULL;
+
+CREATE INDEX IF NOT EXISTS idx_users_ip_hash
+    ON users (ip_hash);
+
+CREATE INDEX IF NOT EXISTS idx_users_unredacted
+    ON users (created_at) WHERE redacted_at IS NULL;
\ No newline at end of file
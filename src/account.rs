@@ -0,0 +1,204 @@
+use crate::{
+    invites::Invite, messages::FullMessage, reactions::{recount, ReactionCount}, reputation::{self, ReputationEntry}, user::User
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use std::str::FromStr;
+use uuid::Uuid;
+
+/// Everything we hold about one user, as handed back to them.
+#[derive(Serialize)]
+pub struct UserExport {
+    pub exported_at: DateTime<Utc>,
+    pub user: User,
+    // only how many, who they are is theirs
+    pub invited: i64,
+    pub has_recovery_phrase: bool,
+    pub messages: Vec<FullMessage>,
+    pub reactions: Vec<ExportedReaction>,
+    pub reports: Vec<ExportedReport>,
+    pub reputation: Vec<ReputationEntry>,
+    pub invites: Vec<Invite>
+}
+
+#[derive(Serialize, FromRow)]
+pub struct ExportedReaction {
+    pub message: Uuid,
+    pub emoji: String,
+    pub created_at: DateTime<Utc>
+}
+
+#[derive(Serialize, FromRow)]
+pub struct ExportedReport {
+    pub message: Uuid,
+    pub reason: String,
+    pub note: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub upheld: Option<bool>
+}
+
+pub async fn export_user(pool: &PgPool, user: User) -> sqlx::Result<UserExport> {
+    let messages = sqlx::query_as!(
+        FullMessage,
+        // language=postgresql
+        "SELECT * FROM messages WHERE author = $1 ORDER BY created_at",
+        user.id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let reactions = sqlx::query_as!(
+        ExportedReaction,
+        // language=postgresql
+        "SELECT message, emoji, created_at FROM reactions WHERE author = $1 ORDER BY created_at",
+        user.id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let reports = sqlx::query_as!(
+        ExportedReport,
+        // language=postgresql
+        "SELECT message, reason, note, created_at, upheld FROM reports
+         WHERE reporter = $1 ORDER BY created_at",
+        user.id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let invites = sqlx::query_as!(
+        Invite,
+        // language=postgresql
+        "SELECT * FROM invites WHERE owner = $1 ORDER BY created_at",
+        user.id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let counts = sqlx::query!(
+        // language=postgresql
+        r#"SELECT (SELECT count(*) FROM users WHERE user_referral = $1) AS "invited!",
+                  EXISTS (SELECT 1 FROM recovery_phrases WHERE user_id = $1) AS "has_recovery_phrase!""#,
+        user.id
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(UserExport {
+        exported_at: Utc::now(),
+        reputation: reputation::all_events(pool, user.id).await?,
+        user,
+        invited: counts.invited,
+        has_recovery_phrase: counts.has_recovery_phrase,
+        messages,
+        reactions,
+        reports,
+        invites
+    })
+}
+
+/// What happens to the people a deleted user invited.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReferralPolicy {
+    // they move up to whoever invited the deleted user, keeping their branch and board
+    Reparent,
+    // they become roots of their own branches
+    Detach
+}
+
+impl FromStr for ReferralPolicy {
+    type Err = ();
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "reparent" => Ok(Self::Reparent),
+            "detach" => Ok(Self::Detach),
+            _ => Err(())
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DeleteConfirmation {
+    // the user's own code, so a stray request can't do this
+    pub code: String
+}
+
+/// What went with a deleted user, for clients that still show any of it.
+pub struct Deletion {
+    // their messages and every reply to them
+    pub messages: Vec<Uuid>,
+    // counts on messages that stay, now without their reactions
+    pub reactions: Vec<ReactionCount>
+}
+
+/// Deletes `user` along with everything they wrote and any replies to it.
+pub async fn delete_user(
+    pool: &PgPool,
+    user: &User,
+    policy: ReferralPolicy
+) -> sqlx::Result<Deletion> {
+    let mut transaction = pool.begin().await?;
+
+    // replies from other people cascade along with the messages they answer
+    let messages = sqlx::query_scalar!(
+        // language=postgresql
+        r#"WITH RECURSIVE doomed AS (
+            SELECT id FROM messages WHERE author = $1
+            UNION
+            SELECT m.id FROM messages m JOIN doomed d ON m.parent_id = d.id
+        )
+        SELECT id AS "id!" FROM doomed"#,
+        user.id
+    )
+    .fetch_all(&mut *transaction)
+    .await?;
+
+    // a banned user's reactions never counted, so nothing anyone sees changes
+    let reacted = if user.banned {
+        Vec::new()
+    } else {
+        sqlx::query!(
+            // language=postgresql
+            "SELECT message, emoji FROM reactions WHERE author = $1 AND NOT message = ANY($2)",
+            user.id,
+            &messages
+        )
+        .fetch_all(&mut *transaction)
+        .await?
+        .into_iter()
+        .map(|reaction| (reaction.message, reaction.emoji))
+        .collect()
+    };
+
+    // detaching is what the foreign key does by itself
+    if matches!(policy, ReferralPolicy::Reparent) {
+        sqlx::query!(
+            // language=postgresql
+            "UPDATE users
+             SET user_referral = $2, location_referral = COALESCE(location_referral, $3)
+             WHERE user_referral = $1",
+            user.id,
+            user.user_referral,
+            user.location_referral
+        )
+        .execute(&mut *transaction)
+        .await?;
+    }
+
+    sqlx::query!(
+        // language=postgresql
+        "DELETE FROM users WHERE id = $1",
+        user.id
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    let reactions = recount(&mut *transaction, user.id, &reacted).await?;
+
+    transaction.commit().await?;
+
+    Ok(Deletion { messages, reactions })
+}
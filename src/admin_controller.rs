@@ -1,16 +1,32 @@
 use crate::{
-    fallback, messages::FullMessage, user::User, util::WR, ws::WebsocketActorMessage, AppState
+    announcements::{active_announcements, create_announcement, remove_announcement, Announcement}, boards::{assign_location, create_board, list_boards, Board}, codes::{code_stats, CodeStats}, fallback, messages::FullMessage, presence::PresenceReport, reports::{report_queue, resolve_reports, QueuedReport}, reputation::{self, record, record_for_referrer, ReputationEntry, ReputationEvent}, retention::{related_users, RelatedUser}, schedule::{cancel_message, pending_messages, schedule_message, ScheduledMessage}, search::{search_messages, SearchQuery, SearchResult}, user::User, util::{clean, WR}, ws::WebsocketActorMessage, AppState
 };
+use anyhow::anyhow;
+use chrono::{DateTime, TimeDelta, Utc};
 use axum::{
-    extract::{Path, Request, State}, middleware::{from_fn_with_state, Next}, response::Response, routing::{get, patch}, Json, RequestExt, Router
+    extract::{Path, Query, Request, State}, http::StatusCode, middleware::{from_fn_with_state, Next}, response::{IntoResponse, Response}, routing::{delete, get, patch}, Json, RequestExt, Router
 };
 use serde::Deserialize;
+use tokio::sync::oneshot;
 use uuid::Uuid;
 
 pub fn admin_controller(state: AppState) -> Router<AppState> {
     Router::new()
         .route("/user/{id}", get(get_user).patch(update_user))
+        .route("/user/{id}/reputation", get(get_reputation))
+        .route("/user/{id}/related", get(get_related_users))
         .route("/message/{id}", patch(update_message))
+        .route("/presence", get(get_presence))
+        .route("/search", get(search))
+        .route("/boards", get(get_boards).post(add_board))
+        .route("/announcements", get(get_announcements).post(add_announcement))
+        .route("/announcements/{id}", delete(take_down_announcement))
+        .route("/scheduled", get(get_scheduled).post(add_scheduled))
+        .route("/scheduled/{id}", delete(cancel_scheduled))
+        .route("/reports", get(get_reports))
+        .route("/reports/{message}", patch(close_reports))
+        .route("/location/{code}", patch(update_location))
+        .route("/codes", get(get_code_stats))
         .layer(from_fn_with_state(state, verify_admin_layer))
 }
 
@@ -49,7 +65,15 @@ async fn get_user(
 #[derive(Deserialize)]
 struct PatchUserPayload {
     #[serde(default)]
-    banned: Option<bool>
+    banned: Option<bool>,
+    // freezing someone freezes everyone they brought in too
+    #[serde(default)]
+    invites_frozen: Option<bool>,
+    #[serde(default)]
+    invite_limit: Option<i32>,
+    // back to the configured quota, wins over `invite_limit`
+    #[serde(default)]
+    clear_invite_limit: bool
 }
 
 async fn update_user(
@@ -57,20 +81,50 @@ async fn update_user(
     Path(id): Path<Uuid>,
     Json(payload): Json<PatchUserPayload>
 ) -> WR<Json<User>> {
-    sqlx::query_as!(
+    let was_banned = sqlx::query_scalar!("SELECT banned FROM users WHERE id = $1", id)
+        .fetch_one(&pool)
+        .await?;
+
+    let user = sqlx::query_as!(
         User,
         // language=postgresql
         "UPDATE users 
-        SET banned = COALESCE($2, banned)
+        SET banned = COALESCE($2, banned),
+            invites_frozen = COALESCE($3, invites_frozen),
+            invite_limit = CASE WHEN $5 THEN NULL ELSE COALESCE($4, invite_limit) END
         WHERE id = $1 RETURNING *
         ",
         id,
-        payload.banned
+        payload.banned,
+        payload.invites_frozen,
+        payload.invite_limit.map(|limit| limit.max(0)),
+        payload.clear_invite_limit
     )
     .fetch_one(&pool)
-    .await
-    .map(Json)
-    .map_err(Into::into)
+    .await?;
+
+    // whoever invited them answers for it
+    if user.banned != was_banned {
+        let event =
+            if user.banned { ReputationEvent::InviteeBanned } else { ReputationEvent::InviteeUnbanned };
+        record_for_referrer(&pool, user.id, event).await?;
+    }
+
+    Ok(Json(user))
+}
+
+async fn get_reputation(
+    State(AppState { pool, .. }): State<AppState>,
+    Path(id): Path<Uuid>
+) -> WR<Json<Vec<ReputationEntry>>> {
+    Ok(Json(reputation::history(&pool, id).await?))
+}
+
+async fn get_related_users(
+    State(AppState { pool, .. }): State<AppState>,
+    Path(id): Path<Uuid>
+) -> WR<Json<Vec<RelatedUser>>> {
+    Ok(Json(related_users(&pool, id).await?))
 }
 
 #[derive(Deserialize)]
@@ -82,10 +136,17 @@ struct PatchMessagePayload {
 }
 
 async fn update_message(
-    State(AppState { pool, tx }): State<AppState>,
+    State(AppState { pool, tx, .. }): State<AppState>,
     Path(id): Path<Uuid>,
     Json(payload): Json<PatchMessagePayload>
 ) -> WR<Json<FullMessage>> {
+    let was_published = sqlx::query_scalar!("SELECT published FROM messages WHERE id = $1", id)
+        .fetch_one(&pool)
+        .await?;
+
+    // edits go out to every board and into search highlights, same as anything posted
+    let content = payload.content.as_deref().map(clean);
+
     let updated_message = sqlx::query_as!(
         FullMessage,
         // language=postgresql
@@ -94,15 +155,196 @@ async fn update_message(
             published = COALESCE($3, published)
         WHERE id = $1 RETURNING *",
         id,
-        payload.content,
+        content,
         payload.published
     )
     .fetch_one(&pool)
     .await?;
 
+    if updated_message.published != was_published {
+        let event = if updated_message.published {
+            ReputationEvent::MessageApproved
+        } else {
+            ReputationEvent::MessageRejected
+        };
+        record(&pool, updated_message.author, event).await?;
+    }
+
     let _ = tx
         .send(WebsocketActorMessage::Message { message: updated_message.clone(), is_update: true })
         .await;
 
     Ok(Json(updated_message))
 }
+
+async fn get_presence(State(AppState { tx, .. }): State<AppState>) -> WR<Json<PresenceReport>> {
+    let (reply, report) = oneshot::channel();
+    tx.send(WebsocketActorMessage::RequestPresence { reply })
+        .await
+        .map_err(|_| anyhow!("websocket actor is gone"))?;
+
+    Ok(Json(report.await?))
+}
+
+async fn search(
+    State(AppState { pool, .. }): State<AppState>,
+    Query(query): Query<SearchQuery>
+) -> WR<Json<Vec<SearchResult>>> {
+    Ok(Json(search_messages(&pool, &query).await?))
+}
+
+async fn get_boards(State(AppState { pool, .. }): State<AppState>) -> WR<Json<Vec<Board>>> {
+    Ok(Json(list_boards(&pool).await?))
+}
+
+#[derive(Deserialize)]
+struct NewBoardPayload {
+    name: String
+}
+
+async fn add_board(
+    State(AppState { pool, .. }): State<AppState>,
+    Json(payload): Json<NewBoardPayload>
+) -> WR<Response> {
+    let name = payload.name.trim();
+    if name.is_empty() {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    }
+
+    Ok(Json(create_board(&pool, name).await?).into_response())
+}
+
+#[derive(Deserialize)]
+struct PatchLocationPayload {
+    // leaving it out sends the location back to the global board
+    #[serde(default)]
+    board_id: Option<Uuid>
+}
+
+async fn update_location(
+    State(AppState { pool, .. }): State<AppState>,
+    Path(code): Path<String>,
+    Json(payload): Json<PatchLocationPayload>
+) -> WR<StatusCode> {
+    if assign_location(&pool, &code, payload.board_id).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Ok(StatusCode::NOT_FOUND)
+    }
+}
+
+async fn get_code_stats(State(AppState { pool, .. }): State<AppState>) -> WR<Json<CodeStats>> {
+    Ok(Json(code_stats(&pool).await?))
+}
+
+async fn get_announcements(
+    State(AppState { pool, .. }): State<AppState>
+) -> WR<Json<Vec<Announcement>>> {
+    Ok(Json(active_announcements(&pool, None).await?))
+}
+
+#[derive(Deserialize)]
+struct NewAnnouncementPayload {
+    content: String,
+    // every board when left out
+    #[serde(default)]
+    board_id: Option<Uuid>,
+    // stays up until taken down when left out
+    #[serde(default)]
+    expires_in_hours: Option<i64>
+}
+
+async fn add_announcement(
+    State(AppState { pool, tx, .. }): State<AppState>,
+    user: User,
+    Json(payload): Json<NewAnnouncementPayload>
+) -> WR<Response> {
+    let expires_in = payload.expires_in_hours.filter(|hours| *hours > 0).map(TimeDelta::hours);
+
+    let Some(announcement) =
+        create_announcement(&pool, user.id, &payload.content, payload.board_id, expires_in).await?
+    else {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    };
+
+    let _ = tx.send(WebsocketActorMessage::Announcement(announcement.clone())).await;
+
+    Ok(Json(announcement).into_response())
+}
+
+async fn take_down_announcement(
+    State(AppState { pool, tx, .. }): State<AppState>,
+    Path(id): Path<Uuid>
+) -> WR<StatusCode> {
+    if !remove_announcement(&pool, id).await? {
+        return Ok(StatusCode::NOT_FOUND);
+    }
+
+    let _ = tx.send(WebsocketActorMessage::AnnouncementRemoved(id)).await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn get_scheduled(
+    State(AppState { pool, .. }): State<AppState>
+) -> WR<Json<Vec<ScheduledMessage>>> {
+    Ok(Json(pending_messages(&pool).await?))
+}
+
+#[derive(Deserialize)]
+struct NewScheduledPayload {
+    content: String,
+    publish_at: DateTime<Utc>,
+    // the global board when left out
+    #[serde(default)]
+    location: Option<String>
+}
+
+async fn add_scheduled(
+    State(AppState { pool, .. }): State<AppState>,
+    user: User,
+    Json(payload): Json<NewScheduledPayload>
+) -> WR<Response> {
+    let location =
+        payload.location.as_deref().map(str::trim).filter(|location| !location.is_empty());
+
+    let Some(scheduled) =
+        schedule_message(&pool, user.id, &payload.content, location, payload.publish_at).await?
+    else {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    };
+
+    Ok(Json(scheduled).into_response())
+}
+
+async fn cancel_scheduled(
+    State(AppState { pool, .. }): State<AppState>,
+    Path(id): Path<Uuid>
+) -> WR<StatusCode> {
+    if cancel_message(&pool, id).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Ok(StatusCode::NOT_FOUND)
+    }
+}
+
+async fn get_reports(State(AppState { pool, .. }): State<AppState>) -> WR<Json<Vec<QueuedReport>>> {
+    Ok(Json(report_queue(&pool).await?))
+}
+
+#[derive(Deserialize)]
+struct ResolveReportsPayload {
+    upheld: bool
+}
+
+async fn close_reports(
+    State(AppState { pool, tx, .. }): State<AppState>,
+    Path(message): Path<Uuid>,
+    Json(payload): Json<ResolveReportsPayload>
+) -> WR<StatusCode> {
+    if let Some(message) = resolve_reports(&pool, message, payload.upheld).await? {
+        let _ = tx.send(WebsocketActorMessage::Message { message, is_update: true }).await;
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
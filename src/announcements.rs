@@ -0,0 +1,81 @@
+use crate::util::clean;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::Serialize;
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+/// Admin notices pinned above a board's messages until they expire or are taken down.
+#[derive(Serialize, FromRow, Clone)]
+pub struct Announcement {
+    pub id: Uuid,
+    pub content: String,
+    pub author: Uuid,
+    // null shows it on every board
+    pub board_id: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>
+}
+
+impl Announcement {
+    pub fn shows_on(&self, board: Uuid) -> bool {
+        self.board_id.is_none_or(|board_id| board_id == board)
+    }
+}
+
+/// Unexpired announcements, newest first. Without a board, every one of them.
+pub async fn active_announcements(
+    pool: &PgPool,
+    board: Option<Uuid>
+) -> sqlx::Result<Vec<Announcement>> {
+    sqlx::query_as!(
+        Announcement,
+        // language=postgresql
+        "SELECT * FROM announcements
+         WHERE (expires_at IS NULL OR expires_at > now())
+           AND ($1::uuid IS NULL OR board_id IS NULL OR board_id = $1)
+         ORDER BY created_at DESC",
+        board
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Returns nothing if there is nothing left to announce once the content is cleaned.
+pub async fn create_announcement(
+    pool: &PgPool,
+    author: Uuid,
+    content: &str,
+    board: Option<Uuid>,
+    expires_in: Option<TimeDelta>
+) -> sqlx::Result<Option<Announcement>> {
+    let content = clean(content.trim());
+    if content.is_empty() {
+        return Ok(None);
+    }
+
+    sqlx::query_as!(
+        Announcement,
+        // language=postgresql
+        "INSERT INTO announcements (content, author, board_id, expires_at)
+         VALUES ($1, $2, $3, $4) RETURNING *",
+        content,
+        author,
+        board,
+        expires_in.map(|ttl| Utc::now() + ttl)
+    )
+    .fetch_one(pool)
+    .await
+    .map(Some)
+}
+
+/// Returns false if there was no such announcement.
+pub async fn remove_announcement(pool: &PgPool, id: Uuid) -> sqlx::Result<bool> {
+    sqlx::query!(
+        // language=postgresql
+        "DELETE FROM announcements WHERE id = $1",
+        id
+    )
+    .execute(pool)
+    .await
+    .map(|result| result.rows_affected() > 0)
+}
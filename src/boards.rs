@@ -0,0 +1,98 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+/// Seeded by the boards migration. Users without a location board read this one,
+/// and crossposts from every other board show up on it.
+pub const GLOBAL_BOARD: Uuid = Uuid::nil();
+
+/// Narrows admin views down to what shows on one board.
+#[derive(Deserialize)]
+pub struct BoardFilter {
+    pub board: Option<Uuid>
+}
+
+#[derive(Serialize, FromRow)]
+pub struct Board {
+    pub id: Uuid,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    // location codes that lead here
+    pub locations: Vec<String>
+}
+
+pub async fn list_boards(pool: &PgPool) -> sqlx::Result<Vec<Board>> {
+    sqlx::query_as!(
+        Board,
+        // language=postgresql
+        r#"SELECT id, name, created_at,
+                  ARRAY(SELECT code FROM locations
+                        WHERE COALESCE(locations.board_id, $1) = boards.id
+                        ORDER BY code) AS "locations!"
+           FROM boards ORDER BY created_at"#,
+        GLOBAL_BOARD
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn create_board(pool: &PgPool, name: &str) -> sqlx::Result<Board> {
+    sqlx::query_as!(
+        Board,
+        // language=postgresql
+        r#"INSERT INTO boards (name) VALUES ($1)
+           RETURNING id, name, created_at, ARRAY[]::text[] AS "locations!""#,
+        name
+    )
+    .fetch_one(pool)
+    .await
+}
+
+/// Adds a location code leading to `board`, or to the global board. Returns false if the code
+/// is already taken.
+pub async fn create_location(
+    pool: &PgPool,
+    code: &str,
+    description: Option<&str>,
+    board: Option<Uuid>
+) -> sqlx::Result<bool> {
+    let board = board.filter(|board| *board != GLOBAL_BOARD);
+
+    sqlx::query!(
+        // language=postgresql
+        "INSERT INTO locations (code, description, board_id) VALUES ($1, $2, $3)
+         ON CONFLICT (code) DO NOTHING",
+        code.to_lowercase(),
+        description,
+        board
+    )
+    .execute(pool)
+    .await
+    .map(|result| result.rows_affected() > 0)
+}
+
+/// Points a location at `board`, or back at the global board. Returns false if there is no such location.
+pub async fn assign_location(pool: &PgPool, code: &str, board: Option<Uuid>) -> sqlx::Result<bool> {
+    let board = board.filter(|board| *board != GLOBAL_BOARD);
+
+    sqlx::query!(
+        // language=postgresql
+        "UPDATE locations SET board_id = $2 WHERE code = $1",
+        code.to_lowercase(),
+        board
+    )
+    .execute(pool)
+    .await
+    .map(|result| result.rows_affected() > 0)
+}
+
+pub async fn board_name(pool: &PgPool, board: Uuid) -> sqlx::Result<Option<String>> {
+    sqlx::query_scalar!(
+        // language=postgresql
+        "SELECT name FROM boards WHERE id = $1",
+        board
+    )
+    .fetch_optional(pool)
+    .await
+}
@@ -1,8 +1,8 @@
-use crate::user::User;
+use crate::{config::config, reputation::Standing, user::User};
 use chrono::{Duration, Utc};
-use rustrict::Type;
+use rustrict::{Censor, Type};
 use sqlx::{FromRow, PgPool};
-use std::{cell::LazyCell, str::FromStr};
+use std::cell::LazyCell;
 
 const TYPE_SCORE_MAP: &[(Type, f32)] = &[
     (Type::PROFANE, -0.1),
@@ -16,30 +16,33 @@ const TYPE_SCORE_MAP: &[(Type, f32)] = &[
     (Type::SEVERE, 15.0)
 ];
 
-struct Thresholds {
+/// The parts of `config().moderation` that bend with someone's standing. Severe content and the
+/// flood limit apply to everyone the same.
+struct Limits {
     spam: f32,
     harassment: f32,
     auto_hide: f32,
-    severe_content: f32,
-    rate_limit_ms: i64,
     max_msgs_per_min: usize,
     max_unpublished: usize
 }
 
-fn env_or<T: FromStr>(name: &str, default: T) -> T {
-    dotenvy::var(name).ok().and_then(|s| s.parse().ok()).unwrap_or(default)
+impl Limits {
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss, clippy::cast_precision_loss)]
+    fn for_user(user: &User) -> Self {
+        let thresholds = &config().moderation;
+        let leniency = Standing::of(user).leniency();
+        let scale = |limit: usize| ((limit as f32 * leniency).round() as usize).max(1);
+
+        Self {
+            spam: thresholds.spam * leniency,
+            harassment: thresholds.harassment * leniency,
+            auto_hide: thresholds.auto_hide * leniency,
+            max_msgs_per_min: scale(thresholds.max_msgs_per_min),
+            max_unpublished: scale(thresholds.max_unpublished)
+        }
+    }
 }
 
-const THRESHOLDS: LazyCell<Thresholds> = LazyCell::new(|| Thresholds {
-    spam: env_or("SPAM_THRESHOLD", 0.4),
-    harassment: env_or("HARASSMENT_THRESHOLD", 0.75),
-    auto_hide: env_or("AUTO_HIDE_THRESHOLD", 0.55),
-    severe_content: env_or("SEVERE_CONTENT_THRESHOLD", 0.85),
-    rate_limit_ms: env_or("RATE_LIMIT_MS", 350),
-    max_msgs_per_min: env_or("MAX_MSGS_PER_MIN", 12),
-    max_unpublished: env_or("MAX_UNPUBLISHED", 20)
-});
-
 const SCORE_UPPER_BOUND: LazyCell<f32> = LazyCell::new(|| {
     let mut threshes = TYPE_SCORE_MAP.iter().map(|(_, s)| *s).collect::<Vec<_>>();
 
@@ -83,10 +86,12 @@ pub async fn censor(
         return CensorOutcome::Allow;
     }
 
-    if user.banned || profanity_type.is(Type::SEVERE) || score >= THRESHOLDS.severe_content {
+    if user.banned || profanity_type.is(Type::SEVERE) || score >= config().moderation.severe_content {
         return CensorOutcome::Hide;
     }
 
+    let limits = Limits::for_user(user);
+
     let messages = sqlx::query_as!(
         PartialMessage,
         // language=postgresql
@@ -104,7 +109,7 @@ pub async fn censor(
 
     let now = Utc::now();
     if let Some(latest) = messages.first() {
-        if now - latest.created_at < Duration::milliseconds(THRESHOLDS.rate_limit_ms)
+        if now - latest.created_at < Duration::milliseconds(config().moderation.rate_limit_ms)
             && messages.len() >= 3
         {
             return CensorOutcome::Block;
@@ -114,12 +119,12 @@ pub async fn censor(
     let recent_count =
         messages.iter().take(10).filter(|m| now - m.created_at < Duration::minutes(1)).count();
 
-    if recent_count >= THRESHOLDS.max_msgs_per_min {
+    if recent_count >= limits.max_msgs_per_min {
         return CensorOutcome::Hide;
     }
 
     let unpublished = messages.iter().filter(|m| !m.published).count();
-    if unpublished >= THRESHOLDS.max_unpublished {
+    if unpublished >= limits.max_unpublished {
         return CensorOutcome::Hide;
     }
 
@@ -127,13 +132,32 @@ pub async fn censor(
     let avg_score =
         messages.iter().take(5).map(|m| m.score).sum::<f32>() / 5.0_f32.min(messages.len() as f32);
 
-    if avg_score > THRESHOLDS.harassment && score > THRESHOLDS.spam {
+    if avg_score > limits.harassment && score > limits.spam {
         return CensorOutcome::Hide;
     }
 
-    if score > THRESHOLDS.auto_hide {
+    if score > limits.auto_hide {
         return CensorOutcome::Hide;
     }
 
     CensorOutcome::Allow
 }
+
+/// What the content checks alone make of `content` from `user` today, leaving out the rate and
+/// history limits that only meant something when it was sent. Returns the score and whether
+/// it would be held back.
+pub fn rescore(user: &User, content: &str) -> (f32, bool) {
+    let profanity_type = Censor::from_str(content).analyze();
+    let score = score_content(profanity_type);
+
+    if user.admin {
+        return (score, false);
+    }
+
+    let held = user.banned
+        || profanity_type.is(Type::SEVERE)
+        || score >= config().moderation.severe_content
+        || score > Limits::for_user(user).auto_hide;
+
+    (score, held)
+}
@@ -0,0 +1,256 @@
+use crate::{
+    account::export_user, boards::create_location, censor::rescore, health::probe, messages::FullMessage, reputation::{record_for_referrer, ReputationEvent}, user::User, MIGRATOR
+};
+use anyhow::{bail, Context};
+use clap::{Parser, Subcommand};
+use sqlx::{migrate::Migrate, PgPool};
+use std::{
+    collections::{hash_map::Entry, HashMap}, fs::File, io, path::PathBuf
+};
+use uuid::Uuid;
+
+#[derive(Parser)]
+#[command(about = "anonymous message board that tracks how everyone got in")]
+pub struct Cli {
+    /// TOML config to use instead of `CONFIG_FILE` or ./config.toml
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+    /// Print the config with secrets redacted, then exit
+    #[arg(long, global = true)]
+    pub print_config: bool,
+    #[command(subcommand)]
+    pub command: Option<Command>
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Apply or roll back database migrations
+    Migrate {
+        #[command(subcommand)]
+        action: MigrateAction
+    },
+    /// Make a user an admin
+    Promote { user: String },
+    /// Take admin away from a user
+    Demote { user: String },
+    /// Add a location code for signups to come in through
+    CreateLocation {
+        code: String,
+        #[arg(long)]
+        description: Option<String>,
+        /// Name of the board it leads to, the global board otherwise
+        #[arg(long)]
+        board: Option<String>
+    },
+    /// Ban a user, or lift a ban with --lift
+    Ban {
+        user: String,
+        #[arg(long)]
+        lift: bool
+    },
+    /// Write everything held about a user as JSON
+    Export {
+        user: String,
+        /// File to write to instead of stdout
+        #[arg(long, short)]
+        output: Option<PathBuf>
+    },
+    /// Run past messages back through moderation
+    Moderation {
+        #[command(subcommand)]
+        action: ModerationAction
+    },
+    /// Exit successfully if the server running here is ready, for container health checks
+    Healthcheck
+}
+
+#[derive(Subcommand)]
+pub enum MigrateAction {
+    /// Apply everything pending
+    Run,
+    /// Roll back the most recent migrations
+    Revert {
+        #[arg(long, default_value_t = 1)]
+        steps: usize
+    }
+}
+
+#[derive(Subcommand)]
+pub enum ModerationAction {
+    /// Rescore messages with the current filter and thresholds and list what would now be held
+    Replay {
+        /// How far back to go
+        #[arg(long, default_value_t = 7)]
+        days: i32,
+        /// Save the new scores and unpublish what would now be held
+        #[arg(long)]
+        apply: bool
+    }
+}
+
+/// Runs an operational command against the same database the server uses. Without one the
+/// binary serves as it always has.
+pub async fn run(command: Command, pool: &PgPool) -> anyhow::Result<()> {
+    match command {
+        Command::Migrate { action: MigrateAction::Run } => {
+            MIGRATOR.run(pool).await?;
+            println!("migrations are up to date");
+        }
+        Command::Migrate { action: MigrateAction::Revert { steps } } => revert(pool, steps).await?,
+        Command::Promote { user } => set_admin(pool, &user, true).await?,
+        Command::Demote { user } => set_admin(pool, &user, false).await?,
+        Command::CreateLocation { code, description, board } => {
+            let board = match board {
+                Some(name) => Some(
+                    sqlx::query_scalar!(
+                        // language=postgresql
+                        "SELECT id FROM boards WHERE name = $1",
+                        name
+                    )
+                    .fetch_optional(pool)
+                    .await?
+                    .with_context(|| format!("no board named {name:?}"))?
+                ),
+                None => None
+            };
+
+            if !create_location(pool, &code, description.as_deref(), board).await? {
+                bail!("location {code:?} already exists");
+            }
+            println!("created location {}", code.to_lowercase());
+        }
+        Command::Ban { user, lift } => ban(pool, &user, !lift).await?,
+        Command::Export { user, output } => {
+            let export = export_user(pool, find_user(pool, &user).await?).await?;
+            match output {
+                Some(path) => serde_json::to_writer_pretty(File::create(path)?, &export)?,
+                None => serde_json::to_writer_pretty(io::stdout(), &export)?
+            }
+        }
+        Command::Moderation { action: ModerationAction::Replay { days, apply } } => {
+            replay(pool, days, apply).await?;
+        }
+        Command::Healthcheck => probe().await?
+    }
+
+    Ok(())
+}
+
+// by id or by code, whichever was handy
+async fn find_user(pool: &PgPool, user: &str) -> anyhow::Result<User> {
+    sqlx::query_as!(
+        User,
+        // language=postgresql
+        "SELECT * FROM users WHERE id = $1 OR code = $2",
+        user.parse::<Uuid>().ok(),
+        user
+    )
+    .fetch_optional(pool)
+    .await?
+    .with_context(|| format!("no user with the id or code {user:?}"))
+}
+
+async fn revert(pool: &PgPool, steps: usize) -> anyhow::Result<()> {
+    let mut connection = pool.acquire().await?;
+    connection.ensure_migrations_table().await?;
+
+    let mut applied = connection.list_applied_migrations().await?;
+    applied.sort_by_key(|migration| std::cmp::Reverse(migration.version));
+
+    // everything newer than the first migration we keep is undone
+    let target = applied.get(steps).map_or(0, |migration| migration.version);
+    MIGRATOR.undo(pool, target).await?;
+
+    println!("reverted {} migrations", steps.min(applied.len()));
+    Ok(())
+}
+
+async fn set_admin(pool: &PgPool, user: &str, admin: bool) -> anyhow::Result<()> {
+    let user = find_user(pool, user).await?;
+
+    sqlx::query!(
+        // language=postgresql
+        "UPDATE users SET admin = $2 WHERE id = $1",
+        user.id,
+        admin
+    )
+    .execute(pool)
+    .await?;
+
+    println!("{} is {} an admin", user.code, if admin { "now" } else { "no longer" });
+    Ok(())
+}
+
+// same as banning from the admin page, reputation included
+async fn ban(pool: &PgPool, user: &str, banned: bool) -> anyhow::Result<()> {
+    let user = find_user(pool, user).await?;
+
+    if user.banned != banned {
+        sqlx::query!(
+            // language=postgresql
+            "UPDATE users SET banned = $2 WHERE id = $1",
+            user.id,
+            banned
+        )
+        .execute(pool)
+        .await?;
+
+        let event =
+            if banned { ReputationEvent::InviteeBanned } else { ReputationEvent::InviteeUnbanned };
+        record_for_referrer(pool, user.id, event).await?;
+    }
+
+    println!("{} is {}", user.code, if banned { "banned" } else { "not banned" });
+    Ok(())
+}
+
+/// Only ever holds messages back, anything held that would pass now stays with the moderators.
+async fn replay(pool: &PgPool, days: i32, apply: bool) -> anyhow::Result<()> {
+    let messages = sqlx::query_as!(
+        FullMessage,
+        // language=postgresql
+        "SELECT * FROM messages WHERE created_at > now() - make_interval(days => $1)
+         ORDER BY created_at",
+        days
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut authors = HashMap::new();
+    let (mut newly_held, mut would_pass) = (0, 0);
+
+    for message in &messages {
+        let author = match authors.entry(message.author) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(find_user(pool, &message.author.to_string()).await?)
+        };
+
+        let (score, held) = rescore(author, &message.content);
+        if message.published && held {
+            newly_held += 1;
+            println!("{} {score:.2} {}: {}", message.id, author.code, message.content);
+        } else if !message.published && !held {
+            would_pass += 1;
+        }
+
+        if apply {
+            sqlx::query!(
+                // language=postgresql
+                "UPDATE messages SET score = $2, published = published AND NOT $3 WHERE id = $1",
+                message.id,
+                score,
+                held
+            )
+            .execute(pool)
+            .await?;
+        }
+    }
+
+    println!(
+        "replayed {} messages, {newly_held} would now be held{}, {would_pass} held ones would pass",
+        messages.len(),
+        if apply { " and were unpublished" } else { "" }
+    );
+
+    Ok(())
+}
@@ -0,0 +1,295 @@
+use crate::{config::ProxyConfig, AppState};
+use anyhow::anyhow;
+use axum::{
+    extract::{ConnectInfo, FromRequestParts}, http::{request::Parts, HeaderMap, HeaderName}
+};
+use std::{
+    convert::Infallible, net::{IpAddr, Ipv4Addr, SocketAddr}, str::FromStr
+};
+
+#[derive(Clone, Copy)]
+struct Cidr {
+    network: IpAddr,
+    prefix: u8
+}
+
+impl FromStr for Cidr {
+    type Err = ();
+
+    fn from_str(cidr: &str) -> Result<Self, Self::Err> {
+        let (network, prefix) = match cidr.split_once('/') {
+            Some((network, prefix)) => (network, Some(prefix)),
+            None => (cidr, None)
+        };
+
+        let network = network.trim().parse::<IpAddr>().map_err(|_| ())?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.map_or(Ok(max), |prefix| prefix.trim().parse::<u8>().map_err(|_| ()))?;
+
+        if prefix > max {
+            return Err(());
+        }
+
+        Ok(Self { network, prefix })
+    }
+}
+
+impl Cidr {
+    fn contains(self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix)).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix)).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false
+        }
+    }
+}
+
+enum IpHeader {
+    // a single address set by the proxy itself, like CF-Connecting-IP or X-Real-IP
+    Single(HeaderName),
+    XForwardedFor,
+    Forwarded
+}
+
+/// Works out who a request came from. Headers are only believed when the peer is one of
+/// our proxies, and only as far back as the chain is made of our proxies.
+pub struct IpResolver {
+    trusted: Vec<Cidr>,
+    headers: Vec<IpHeader>
+}
+
+impl IpResolver {
+    pub fn new(config: &ProxyConfig) -> anyhow::Result<Self> {
+        let trusted = config
+            .trusted
+            .iter()
+            .map(|cidr| {
+                cidr.parse().map_err(|()| {
+                    anyhow!("trusted proxy {cidr:?} isn't an address or cidr")
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        let headers = config
+            .client_ip_headers
+            .iter()
+            .map(|header| match header.to_ascii_lowercase().as_str() {
+                "x-forwarded-for" => Ok(IpHeader::XForwardedFor),
+                "forwarded" => Ok(IpHeader::Forwarded),
+                header => HeaderName::from_bytes(header.as_bytes())
+                    .map(IpHeader::Single)
+                    .map_err(|_| anyhow!("client ip header {header:?} isn't a valid header name"))
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self { trusted, headers })
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    pub fn resolve(&self, peer: Option<IpAddr>, headers: &HeaderMap) -> IpAddr {
+        let Some(peer) = peer.map(|peer| peer.to_canonical()) else {
+            // no socket to speak of, only possible when serving without connect info
+            return IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+        };
+
+        if !self.is_trusted(peer) {
+            return peer;
+        }
+
+        self.headers
+            .iter()
+            .find_map(|header| match header {
+                IpHeader::Single(name) => headers
+                    .get(name)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| parse_node(value.trim())),
+                IpHeader::XForwardedFor => self.walk_chain(
+                    headers
+                        .get_all("x-forwarded-for")
+                        .iter()
+                        .filter_map(|value| value.to_str().ok())
+                        .flat_map(|value| value.split(','))
+                        .map(str::trim)
+                        .collect()
+                ),
+                IpHeader::Forwarded => self.walk_chain(
+                    headers
+                        .get_all("forwarded")
+                        .iter()
+                        .filter_map(|value| value.to_str().ok())
+                        .flat_map(|value| value.split(','))
+                        .filter_map(|element| {
+                            element.split(';').find_map(|pair| {
+                                let (key, value) = pair.split_once('=')?;
+                                key.trim().eq_ignore_ascii_case("for").then(|| value.trim())
+                            })
+                        })
+                        .collect()
+                )
+            })
+            .unwrap_or(peer)
+    }
+
+    // the closest hop that isn't one of ours, anything further back could be made up by the client
+    fn walk_chain(&self, hops: Vec<&str>) -> Option<IpAddr> {
+        let mut client = None;
+
+        for hop in hops.into_iter().rev() {
+            // an obfuscated or garbled hop means we can't see past it, so we don't guess at all
+            let ip = parse_node(hop)?;
+            client = Some(ip);
+
+            if !self.is_trusted(ip) {
+                break;
+            }
+        }
+
+        client
+    }
+}
+
+// plain addresses, or the quoted, bracketed and ported forms `Forwarded` allows
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim_matches('"');
+
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split_once(']').and_then(|(ip, _)| ip.parse().ok());
+    }
+
+    node.parse::<IpAddr>()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|socket| socket.ip()))
+        .map(|ip| ip.to_canonical())
+}
+
+pub struct ClientIp(pub IpAddr);
+
+impl FromRequestParts<AppState> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState
+    ) -> Result<Self, Self::Rejection> {
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(socket)| socket.ip());
+
+        Ok(Self(state.ip_resolver.resolve(peer, &parts.headers)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for &(name, value) in pairs {
+            headers.append(name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn cidr_parsing() {
+        assert!("10.0.0.0/8".parse::<Cidr>().unwrap().contains(ip("10.200.3.4")));
+        assert!(!"10.0.0.0/8".parse::<Cidr>().unwrap().contains(ip("11.0.0.1")));
+        assert!("fc00::/7".parse::<Cidr>().unwrap().contains(ip("fd12::1")));
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(ip("203.0.113.9")));
+        // a bare address is a single host, and mapped v4 addresses still match v4 ranges
+        assert!("127.0.0.1".parse::<Cidr>().unwrap().contains(ip("::ffff:127.0.0.1")));
+        assert!(!"127.0.0.1".parse::<Cidr>().unwrap().contains(ip("127.0.0.2")));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("::/129".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/x".parse::<Cidr>().is_err());
+        assert!("localhost".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn node_forms() {
+        assert_eq!(parse_node("203.0.113.9"), Some(ip("203.0.113.9")));
+        assert_eq!(parse_node("\"203.0.113.9:4711\""), Some(ip("203.0.113.9")));
+        assert_eq!(parse_node("\"[2001:db8::1]:4711\""), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("[2001:db8::1]"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("::ffff:198.51.100.1"), Some(ip("198.51.100.1")));
+        assert_eq!(parse_node("unknown"), None);
+        assert_eq!(parse_node("_hidden"), None);
+    }
+
+    #[test]
+    fn chain_walks_back_to_the_first_untrusted_hop() {
+        let resolver = IpResolver::new(&ProxyConfig::default()).unwrap();
+
+        // the client can prepend whatever it likes, only the hop our proxy saw counts
+        assert_eq!(
+            resolver.walk_chain(vec!["1.1.1.1", "203.0.113.9", "10.0.0.2"]),
+            Some(ip("203.0.113.9"))
+        );
+        // all ours, the furthest one is as close to the client as we get
+        assert_eq!(resolver.walk_chain(vec!["10.0.0.3", "10.0.0.2"]), Some(ip("10.0.0.3")));
+        assert_eq!(resolver.walk_chain(vec!["203.0.113.9", "unknown", "10.0.0.2"]), None);
+        assert_eq!(resolver.walk_chain(vec![]), None);
+    }
+
+    #[test]
+    fn headers_only_count_from_trusted_peers() {
+        let resolver = IpResolver::new(&ProxyConfig::default()).unwrap();
+        let spoofed = headers(&[("x-forwarded-for", "1.1.1.1"), ("forwarded", "for=1.1.1.1")]);
+
+        assert_eq!(resolver.resolve(Some(ip("198.51.100.7")), &spoofed), ip("198.51.100.7"));
+        assert_eq!(resolver.resolve(Some(ip("10.0.0.2")), &spoofed), ip("1.1.1.1"));
+        assert_eq!(
+            resolver.resolve(
+                Some(ip("10.0.0.2")),
+                &headers(&[("forwarded", "for=\"[2001:db8::1]:80\";proto=https")])
+            ),
+            ip("2001:db8::1")
+        );
+        assert_eq!(resolver.resolve(Some(ip("10.0.0.2")), &HeaderMap::new()), ip("10.0.0.2"));
+    }
+
+    #[test]
+    fn single_headers_are_opt_in() {
+        // a proxy that passes CF-Connecting-IP through untouched lets the client choose its ip
+        let spoofed =
+            headers(&[("cf-connecting-ip", "1.1.1.1"), ("x-forwarded-for", "203.0.113.9")]);
+
+        let resolver = IpResolver::new(&ProxyConfig::default()).unwrap();
+        assert_eq!(resolver.resolve(Some(ip("172.17.0.1")), &spoofed), ip("203.0.113.9"));
+
+        let resolver = IpResolver::new(&ProxyConfig {
+            client_ip_headers: vec!["CF-Connecting-IP".into(), "x-forwarded-for".into()],
+            ..ProxyConfig::default()
+        })
+        .unwrap();
+        assert_eq!(resolver.resolve(Some(ip("172.17.0.1")), &spoofed), ip("1.1.1.1"));
+    }
+
+    #[test]
+    fn rejects_bad_config() {
+        let bad_cidr =
+            ProxyConfig { trusted: vec!["10.0.0.0/40".into()], ..ProxyConfig::default() };
+        assert!(IpResolver::new(&bad_cidr).is_err());
+
+        let bad_header =
+            ProxyConfig { client_ip_headers: vec!["x forwarded".into()], ..ProxyConfig::default() };
+        assert!(IpResolver::new(&bad_header).is_err());
+    }
+}
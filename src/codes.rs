@@ -0,0 +1,167 @@
+use crate::config::config;
+use anyhow::anyhow;
+use rand::prelude::IndexedRandom;
+use rustrict::CensorStr;
+use serde::Serialize;
+use sqlx::PgPool;
+use std::{future::Future, sync::LazyLock};
+
+const WORDS_STRING_LIST: &str = include_str!("../assets/all_english_words_clean.txt");
+
+static WORDS: LazyLock<Vec<&str>> = LazyLock::new(|| WORDS_STRING_LIST.lines().collect());
+
+// only counted for stats, checking the whole list takes far too long to do on the way to a code
+static INAPPROPRIATE_WORDS: LazyLock<usize> =
+    LazyLock::new(|| WORDS.iter().filter(|word| word.is_inappropriate()).count());
+
+const SEPARATORS: &[char] = &['-', '_', '.'];
+
+// a handful of collisions in a row means the keyspace is nearly used up, not bad luck
+const CLAIM_ATTEMPTS: usize = 8;
+
+// anything rustrict wouldn't let through as a message can't end up in a code or phrase either
+fn pick_words(count: usize) -> Vec<&'static str> {
+    let mut rng = rand::rng();
+
+    loop {
+        let words = WORDS.choose_multiple(&mut rng, count).copied().collect::<Vec<_>>();
+        if !words.iter().any(|word| word.is_inappropriate()) {
+            return words;
+        }
+    }
+}
+
+pub fn generate_code(words_per_code: usize) -> String {
+    loop {
+        let words = pick_words(words_per_code);
+        let separator = SEPARATORS.choose(&mut rand::rng()).unwrap();
+
+        // fine on their own doesn't mean fine together
+        let code = words.join(&separator.to_string());
+        if !code.is_inappropriate() {
+            return code;
+        }
+    }
+}
+
+/// Space separated words, for things people have to write down rather than share.
+pub fn generate_phrase(words: usize) -> String {
+    pick_words(words).join(" ")
+}
+
+/// Hands fresh codes of `words_per_code` words to `attempt` until it manages to claim one, which
+/// it signals by returning something rather than nothing.
+pub async fn claim_code<T, F>(
+    words_per_code: usize,
+    mut attempt: impl FnMut(String) -> F
+) -> anyhow::Result<T>
+where
+    F: Future<Output = sqlx::Result<Option<T>>>
+{
+    for _ in 0..CLAIM_ATTEMPTS {
+        if let Some(claimed) = attempt(generate_code(words_per_code)).await? {
+            return Ok(claimed);
+        }
+    }
+
+    Err(anyhow!("no free code after {CLAIM_ATTEMPTS} attempts, the keyspace may be running out"))
+}
+
+/// How much room there is for new codes.
+#[derive(Serialize)]
+pub struct CodeStats {
+    pub words: usize,
+    // dropped from the list by the profanity filter
+    pub filtered_words: usize,
+    pub words_per_code: usize,
+    pub keyspace: u128,
+    pub bits: f64,
+    // users' own codes and live invites
+    pub in_use: i64,
+    // odds that a single freshly generated code is already taken
+    pub collision_chance: f64
+}
+
+// words are never repeated within a code, and one separator joins them all
+fn keyspace(words: usize, words_per_code: usize) -> u128 {
+    let separators = if words_per_code > 1 { SEPARATORS.len() } else { 1 };
+
+    (0..words_per_code)
+        .map(|taken| words.saturating_sub(taken) as u128)
+        .fold(separators as u128, u128::saturating_mul)
+}
+
+/// The first call is slow, it has to run the whole word list past the filter.
+#[allow(clippy::cast_precision_loss)]
+pub async fn code_stats(pool: &PgPool) -> sqlx::Result<CodeStats> {
+    let filtered_words = tokio::task::spawn_blocking(|| *INAPPROPRIATE_WORDS).await.unwrap_or(0);
+    let words = WORDS.len() - filtered_words;
+    let words_per_code = config().invites.code_words;
+
+    let keyspace = keyspace(words, words_per_code);
+
+    let in_use = sqlx::query_scalar!(
+        // language=postgresql
+        r#"SELECT (SELECT count(*) FROM users) + (SELECT count(*) FROM invites) AS "count!""#
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(CodeStats {
+        words,
+        filtered_words,
+        words_per_code,
+        keyspace,
+        bits: (keyspace as f64).log2(),
+        in_use,
+        collision_chance: in_use as f64 / keyspace as f64
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyspace_counts_orderings_and_separators() {
+        assert_eq!(keyspace(10, 1), 10);
+        assert_eq!(keyspace(10, 2), 10 * 9 * 3);
+        assert_eq!(keyspace(10, 3), 10 * 9 * 8 * 3);
+        // more words than there are to pick from leaves nothing
+        assert_eq!(keyspace(2, 3), 0);
+        assert_eq!(keyspace(usize::MAX, 6), u128::MAX);
+    }
+
+    #[test]
+    fn picked_words_are_distinct_and_clean() {
+        for _ in 0..100 {
+            let words = pick_words(6);
+
+            assert_eq!(words.len(), 6);
+            assert!(words.iter().all(|word| !word.is_inappropriate()));
+            let distinct = words.iter().collect::<std::collections::HashSet<_>>();
+            assert_eq!(distinct.len(), words.len());
+        }
+
+        assert_eq!(generate_phrase(4).split(' ').count(), 4);
+    }
+
+    #[tokio::test]
+    async fn claims_the_first_free_code() {
+        let mut tried = Vec::new();
+        let claimed = claim_code(3, |code| {
+            tried.push(code.clone());
+            let free = tried.len() == 3;
+            async move { Ok(free.then_some(code)) }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(tried.len(), 3);
+        assert_eq!(claimed, tried[2]);
+        assert_eq!(claimed.split(SEPARATORS).count(), 3);
+
+        let exhausted = claim_code(3, |_| async { Ok(None::<String>) }).await;
+        assert!(exhausted.is_err());
+    }
+}
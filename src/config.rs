@@ -0,0 +1,542 @@
+use crate::{account::ReferralPolicy, client_ip::IpResolver, retention::{HashKey, RedactionMode}};
+use anyhow::{anyhow, bail, Context};
+use serde::{Deserialize, Serialize, Serializer};
+use std::{net::SocketAddr, path::{Path, PathBuf}, str::FromStr, sync::OnceLock};
+use url::Url;
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+// the frame limit in `ws` leaves room for about this many characters of multibyte text
+const MAX_MESSAGE_LENGTH: usize = 1000;
+
+/// Everything the server can be tuned with. Read from a TOML file, then the environment, so
+/// the variables that existed before the file keep working and win over it.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub messages: MessagesConfig,
+    pub moderation: ModerationConfig,
+    pub reports: ReportsConfig,
+    pub reputation: ReputationConfig,
+    pub invites: InvitesConfig,
+    pub accounts: AccountsConfig,
+    pub retention: RetentionConfig,
+    pub proxy: ProxyConfig
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: SocketAddr,
+    // where device links point, they are turned off without it
+    pub public_origin: Option<String>,
+    // messages queued for the websocket actor before senders have to wait
+    pub channel_capacity: usize,
+    // exit when the database can't be migrated instead of serving without it
+    pub strict_startup: bool
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([0, 0, 0, 0], 5000)),
+            public_origin: None,
+            channel_capacity: 100,
+            strict_startup: false
+        }
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    #[serde(serialize_with = "redact_url")]
+    pub url: String
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MessagesConfig {
+    // admins aren't held to this
+    pub max_length: usize,
+    pub user_page_size: i64,
+    pub admin_page_size: i64
+}
+
+impl Default for MessagesConfig {
+    fn default() -> Self {
+        Self { max_length: 320, user_page_size: 50, admin_page_size: 300 }
+    }
+}
+
+/// Scores at or over these get a message held back, before standing is taken into account.
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModerationConfig {
+    pub spam: f32,
+    pub harassment: f32,
+    pub auto_hide: f32,
+    pub severe_content: f32,
+    pub rate_limit_ms: i64,
+    pub max_msgs_per_min: usize,
+    pub max_unpublished: usize
+}
+
+impl Default for ModerationConfig {
+    fn default() -> Self {
+        Self {
+            spam: 0.4,
+            harassment: 0.75,
+            auto_hide: 0.55,
+            severe_content: 0.85,
+            rate_limit_ms: 350,
+            max_msgs_per_min: 12,
+            max_unpublished: 20
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReportsConfig {
+    pub per_hour: i64,
+    // distinct branches reporting a message before it's hidden pending review
+    pub hide_branches: i64
+}
+
+impl Default for ReportsConfig {
+    fn default() -> Self {
+        Self { per_hour: 10, hide_branches: 3 }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReputationConfig {
+    pub new_account_hours: i64,
+    pub probation: f32,
+    pub trusted: f32
+}
+
+impl Default for ReputationConfig {
+    fn default() -> Self {
+        Self { new_account_hours: 24, probation: -5.0, trusted: 10.0 }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InvitesConfig {
+    // per user unless an admin set their own, admins have no quota
+    pub quota: i64,
+    pub code_words: usize
+}
+
+impl Default for InvitesConfig {
+    fn default() -> Self {
+        Self { quota: 10, code_words: 2 }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccountsConfig {
+    pub device_link_minutes: i64,
+    pub deleted_user_referrals: ReferralPolicy
+}
+
+impl Default for AccountsConfig {
+    fn default() -> Self {
+        Self { device_link_minutes: 10, deleted_user_referrals: ReferralPolicy::Reparent }
+    }
+}
+
+/// Every retention period is off at 0 days.
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    pub unpublish_days: i32,
+    pub delete_days: i32,
+    pub network_days: i32,
+    pub network_mode: RedactionMode,
+    pub hash_key: Secret
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            unpublish_days: 0,
+            delete_days: 0,
+            network_days: 0,
+            network_mode: RedactionMode::Hash,
+            hash_key: Secret::default()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyConfig {
+    // addresses and cidrs whose forwarding headers are believed
+    pub trusted: Vec<String>,
+    // tried in order, `x-forwarded-for` and `forwarded` are understood as chains. single address
+    // headers like `cf-connecting-ip` can be sent by anyone, only list one if every trusted peer
+    // sets it itself
+    pub client_ip_headers: Vec<String>
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        // loopback and private ranges, covering cloudflared, docker networks and a local proxy
+        let trusted =
+            ["127.0.0.0/8", "::1/128", "10.0.0.0/8", "172.16.0.0/12", "192.168.0.0/16", "fc00::/7"];
+        let client_ip_headers = ["x-forwarded-for", "forwarded"];
+
+        Self {
+            trusted: trusted.map(String::from).to_vec(),
+            client_ip_headers: client_ip_headers.map(String::from).to_vec()
+        }
+    }
+}
+
+/// Never printed back out.
+#[derive(Default, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for Secret {
+    type Err = ();
+
+    fn from_str(secret: &str) -> Result<Self, Self::Err> {
+        Ok(Self(secret.to_string()))
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(if self.0.is_empty() { "" } else { "<redacted>" })
+    }
+}
+
+// keeps the host and database, which is what you'd want to check, and drops the password
+// wherever postgres would take it from. anything that doesn't parse is hidden whole
+fn redact_url<S: Serializer>(url: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    let Ok(mut parsed) = Url::parse(url) else {
+        return serializer.serialize_str(if url.is_empty() { "" } else { "<redacted>" });
+    };
+
+    if parsed.password().is_some() {
+        let _ = parsed.set_password(Some("redacted"));
+    }
+
+    let pairs = parsed.query_pairs().into_owned().collect::<Vec<_>>();
+    if pairs.iter().any(|(key, _)| key == "password") {
+        parsed.query_pairs_mut().clear().extend_pairs(pairs.iter().map(|(key, value)| {
+            (key.as_str(), if key == "password" { "redacted" } else { value.as_str() })
+        }));
+    }
+
+    serializer.serialize_str(parsed.as_str())
+}
+
+/// Where overrides are looked up, the process environment outside of tests.
+struct Env<F>(F);
+
+impl<F: Fn(&str) -> Option<String>> Env<F> {
+    fn parse<T: FromStr>(&self, name: &str, field: &mut T) -> anyhow::Result<()> {
+        if let Some(value) = (self.0)(name) {
+            *field = value.parse().map_err(|_| anyhow!("{name}={value:?} is not a valid value"))?;
+        }
+
+        Ok(())
+    }
+
+    fn optional(&self, name: &str, field: &mut Option<String>) {
+        if let Some(value) = (self.0)(name) {
+            *field = Some(value).filter(|value| !value.is_empty());
+        }
+    }
+
+    fn list(&self, name: &str, field: &mut Vec<String>) {
+        if let Some(value) = (self.0)(name) {
+            *field = value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(String::from)
+                .collect();
+        }
+    }
+}
+
+impl Config {
+    /// Reads `path` if given, otherwise `CONFIG_FILE` or `config.toml` if either is there,
+    /// then applies the environment. Nothing is checked yet, see `validate`.
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let path = path
+            .map(Path::to_path_buf)
+            .or_else(|| dotenvy::var("CONFIG_FILE").ok().map(PathBuf::from))
+            .or_else(|| Some(PathBuf::from("config.toml")).filter(|path| path.exists()));
+
+        let mut config = match path {
+            Some(path) => {
+                let contents = std::fs::read_to_string(&path)
+                    .with_context(|| format!("failed to read config file {}", path.display()))?;
+                toml::from_str(&contents)
+                    .with_context(|| format!("invalid config file {}", path.display()))?
+            }
+            None => Self::default()
+        };
+
+        config.apply_env(|name| dotenvy::var(name).ok())?;
+
+        Ok(config)
+    }
+
+    fn apply_env(&mut self, lookup: impl Fn(&str) -> Option<String>) -> anyhow::Result<()> {
+        let Self {
+            server,
+            database,
+            messages,
+            moderation,
+            reports,
+            reputation,
+            invites,
+            accounts,
+            retention,
+            proxy
+        } = self;
+        let env = Env(lookup);
+
+        env.parse("BIND_ADDRESS", &mut server.bind)?;
+        env.optional("PUBLIC_ORIGIN", &mut server.public_origin);
+        env.parse("CHANNEL_CAPACITY", &mut server.channel_capacity)?;
+        env.parse("STRICT_STARTUP", &mut server.strict_startup)?;
+        env.parse("DATABASE_URL", &mut database.url)?;
+        env.parse("MAX_MESSAGE_LENGTH", &mut messages.max_length)?;
+        env.parse("USER_PAGE_SIZE", &mut messages.user_page_size)?;
+        env.parse("ADMIN_PAGE_SIZE", &mut messages.admin_page_size)?;
+        env.parse("SPAM_THRESHOLD", &mut moderation.spam)?;
+        env.parse("HARASSMENT_THRESHOLD", &mut moderation.harassment)?;
+        env.parse("AUTO_HIDE_THRESHOLD", &mut moderation.auto_hide)?;
+        env.parse("SEVERE_CONTENT_THRESHOLD", &mut moderation.severe_content)?;
+        env.parse("RATE_LIMIT_MS", &mut moderation.rate_limit_ms)?;
+        env.parse("MAX_MSGS_PER_MIN", &mut moderation.max_msgs_per_min)?;
+        env.parse("MAX_UNPUBLISHED", &mut moderation.max_unpublished)?;
+        env.parse("REPORTS_PER_HOUR", &mut reports.per_hour)?;
+        env.parse("REPORT_HIDE_BRANCHES", &mut reports.hide_branches)?;
+        env.parse("NEW_ACCOUNT_HOURS", &mut reputation.new_account_hours)?;
+        env.parse("PROBATION_REPUTATION", &mut reputation.probation)?;
+        env.parse("TRUSTED_REPUTATION", &mut reputation.trusted)?;
+        env.parse("INVITE_QUOTA", &mut invites.quota)?;
+        env.parse("CODE_WORDS", &mut invites.code_words)?;
+        env.parse("DEVICE_LINK_MINUTES", &mut accounts.device_link_minutes)?;
+        env.parse("DELETED_USER_REFERRALS", &mut accounts.deleted_user_referrals)?;
+        env.parse("RETENTION_UNPUBLISH_DAYS", &mut retention.unpublish_days)?;
+        env.parse("RETENTION_DELETE_DAYS", &mut retention.delete_days)?;
+        env.parse("NETWORK_RETENTION_DAYS", &mut retention.network_days)?;
+        env.parse("NETWORK_RETENTION_MODE", &mut retention.network_mode)?;
+        env.parse("RETENTION_HASH_KEY", &mut retention.hash_key)?;
+        env.list("TRUSTED_PROXIES", &mut proxy.trusted);
+        env.list("CLIENT_IP_HEADERS", &mut proxy.client_ip_headers);
+
+        Ok(())
+    }
+
+    /// Every problem at once, so fixing a config isn't one restart per mistake.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let mut problems = Vec::new();
+        let mut check = |ok: bool, problem: &str| {
+            if !ok {
+                problems.push(problem.to_string());
+            }
+        };
+
+        check(!self.database.url.is_empty(), "database.url (or DATABASE_URL) has to be set");
+        check(self.server.channel_capacity > 0, "server.channel_capacity has to be at least 1");
+        check(
+            self.server.public_origin.as_ref().is_none_or(|origin| origin.starts_with("http")),
+            "server.public_origin has to start with http:// or https://"
+        );
+        check(
+            (1..=MAX_MESSAGE_LENGTH).contains(&self.messages.max_length),
+            &format!("messages.max_length has to be between 1 and {MAX_MESSAGE_LENGTH}")
+        );
+        check(self.messages.user_page_size > 0, "messages.user_page_size has to be at least 1");
+        check(self.messages.admin_page_size > 0, "messages.admin_page_size has to be at least 1");
+
+        let moderation = &self.moderation;
+        for (name, threshold) in [
+            ("spam", moderation.spam),
+            ("harassment", moderation.harassment),
+            ("auto_hide", moderation.auto_hide),
+            ("severe_content", moderation.severe_content)
+        ] {
+            check(threshold > 0.0, &format!("moderation.{name} has to be above 0"));
+        }
+        check(moderation.rate_limit_ms >= 0, "moderation.rate_limit_ms can't be negative");
+        check(moderation.max_msgs_per_min > 0, "moderation.max_msgs_per_min has to be at least 1");
+        check(moderation.max_unpublished > 0, "moderation.max_unpublished has to be at least 1");
+
+        check(self.reports.per_hour > 0, "reports.per_hour has to be at least 1");
+        check(self.reports.hide_branches > 0, "reports.hide_branches has to be at least 1");
+        check(
+            self.reputation.new_account_hours >= 0,
+            "reputation.new_account_hours can't be negative"
+        );
+        check(
+            self.reputation.probation < self.reputation.trusted,
+            "reputation.probation has to be below reputation.trusted"
+        );
+        check(self.invites.quota >= 0, "invites.quota can't be negative");
+        check(
+            (1..=6).contains(&self.invites.code_words),
+            "invites.code_words has to be between 1 and 6"
+        );
+        check(
+            self.accounts.device_link_minutes > 0,
+            "accounts.device_link_minutes has to be at least 1"
+        );
+
+        let retention = &self.retention;
+        check(retention.unpublish_days >= 0, "retention.unpublish_days can't be negative");
+        check(retention.delete_days >= 0, "retention.delete_days can't be negative");
+        check(retention.network_days >= 0, "retention.network_days can't be negative");
+
+        if let Err(why) = HashKey::new(&retention.hash_key) {
+            problems.push(format!("{why:#}"));
+        }
+        if let Err(why) = IpResolver::new(&self.proxy) {
+            problems.push(format!("{why:#}"));
+        }
+
+        if !problems.is_empty() {
+            bail!("invalid config:\n  {}", problems.join("\n  "));
+        }
+
+        Ok(())
+    }
+
+    /// The config with secrets blanked out, as TOML.
+    pub fn redacted(&self) -> anyhow::Result<String> {
+        toml::to_string_pretty(self).map_err(Into::into)
+    }
+
+    /// Makes this the config `config()` hands out. Only the first call counts.
+    pub fn install(self) -> &'static Self {
+        CONFIG.get_or_init(|| self)
+    }
+}
+
+/// The running config, loaded before anything else starts.
+#[allow(clippy::missing_panics_doc)]
+pub fn config() -> &'static Config {
+    CONFIG.get().expect("config read before it was loaded")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn valid() -> Config {
+        let mut config = Config::default();
+        config.database.url = "postgres://board@localhost/board".to_string();
+        config.retention.hash_key = Secret("0123456789abcdef".to_string());
+        config
+    }
+
+    fn redacted_url(url: &str) -> String {
+        let mut config = valid();
+        config.database.url = url.to_string();
+        let redacted = config.redacted().unwrap();
+        toml::from_str::<Config>(&redacted).unwrap().database.url
+    }
+
+    #[test]
+    fn defaults_only_miss_the_deployment_specifics() {
+        let problems = Config::default().validate().unwrap_err().to_string();
+
+        assert!(problems.contains("database.url"));
+        assert!(problems.contains("retention.hash_key"));
+        assert_eq!(problems.lines().count(), 3);
+        valid().validate().unwrap();
+    }
+
+    #[test]
+    fn reports_every_problem_at_once() {
+        let mut config = valid();
+        config.messages.max_length = MAX_MESSAGE_LENGTH + 1;
+        config.reputation.probation = config.reputation.trusted;
+        config.invites.code_words = 0;
+        config.retention.hash_key = Secret("short".to_string());
+        config.proxy.trusted = vec!["not an address".to_string()];
+
+        let problems = config.validate().unwrap_err().to_string();
+        for field in [
+            "messages.max_length",
+            "reputation.probation",
+            "invites.code_words",
+            "retention.hash_key",
+            "not an address"
+        ] {
+            assert!(problems.contains(field), "{field} missing from {problems}");
+        }
+    }
+
+    #[test]
+    fn environment_wins_over_the_file() {
+        let mut environment = HashMap::from([
+            ("INVITE_QUOTA", "3"),
+            ("PUBLIC_ORIGIN", ""),
+            ("TRUSTED_PROXIES", " 10.0.0.1, ,fc00::/7 ")
+        ]);
+
+        let mut config = valid();
+        config.invites.quota = 7;
+        config.server.public_origin = Some("https://example.com".to_string());
+        config.apply_env(|name| environment.get(name).map(ToString::to_string)).unwrap();
+
+        assert_eq!(config.invites.quota, 3);
+        assert_eq!(config.server.public_origin, None);
+        assert_eq!(config.proxy.trusted, ["10.0.0.1", "fc00::/7"]);
+        // untouched without a variable of its own
+        assert_eq!(config.invites.code_words, InvitesConfig::default().code_words);
+
+        environment.insert("INVITE_QUOTA", "lots");
+        let why = config.apply_env(|name| environment.get(name).map(ToString::to_string));
+        assert!(why.unwrap_err().to_string().contains("INVITE_QUOTA"));
+    }
+
+    #[test]
+    fn redacts_passwords_wherever_they_are() {
+        assert_eq!(
+            redacted_url("postgres://board:hunter2@db:5432/board"),
+            "postgres://board:redacted@db:5432/board"
+        );
+        assert_eq!(
+            redacted_url("postgres://db/board?user=board&password=hunter2&sslmode=require"),
+            "postgres://db/board?user=board&password=redacted&sslmode=require"
+        );
+        // a password without a user, which splitting on the colon used to miss
+        assert_eq!(redacted_url("postgres://:hunter2@db/board"), "postgres://:redacted@db/board");
+        assert_eq!(redacted_url("postgres://board@db/board"), "postgres://board@db/board");
+        assert_eq!(redacted_url("host=db password=hunter2"), "<redacted>");
+    }
+
+    #[test]
+    fn redacts_the_hash_key() {
+        let redacted = valid().redacted().unwrap();
+
+        assert!(!redacted.contains("0123456789abcdef"));
+        assert!(redacted.contains("hash_key = \"<redacted>\""));
+    }
+}
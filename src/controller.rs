@@ -1,47 +1,64 @@
 use crate::{
-    censor, censor::{score_content, CensorOutcome}, messages::{FullMessage, StandardMessage}, user::{inject_uuid_cookie, MaybeLocalUserId, User}, util::{
-        clean, generate_code, ClientIp, MaybeUserAgent, MessageAndIvFromHeaders, MinifiedHtml, OptionalExtractor, WR
-    }, ws::WebsocketActorMessage, AppState
+    account::{delete_user, export_user, DeleteConfirmation, Deletion, UserExport}, announcements::{active_announcements, Announcement}, boards::{board_name, BoardFilter, GLOBAL_BOARD}, censor, censor::{score_content, CensorOutcome}, client_ip::ClientIp, codes::claim_code, config::config, history::{admin_history, user_history, Cursor, History, HistoryConnection}, invites::{create_invite, invite_status, redeem, revoke_invite, rotate_code, Invite, InviteStatus, NewInvite}, messages::{FullMessage, StandardMessage, Thread}, reactions::EMOJI, recovery::{create_link, create_phrase, recover, redeem_link, remove_phrase, Recovery}, reports::{file_report, NewReport, ReportOutcome, REPORT_REASONS}, retention::HashKey, user::{inject_uuid_cookie, MaybeLocalUserId, User}, util::{
+        clean, MaybeUserAgent, EnvelopeFromHeaders, MinifiedHtml, OptionalExtractor, WR
+    }, ws::{handle_submission, Submission, WebsocketActorMessage}, AppState
 };
-use aes::cipher::block_padding::Pkcs7;
+use aes_gcm::{
+    aead::{Aead, Payload}, Aes256Gcm
+};
+use anyhow::anyhow;
 use askama::Template;
 use axum::{
-    extract::{Path, State}, http::StatusCode, response::{Html, Response}
-};
-use cbc::{
-    cipher::{BlockDecryptMut, KeyIvInit}, Decryptor
+    extract::{Path, Query, State}, http::{
+        header::{CONTENT_DISPOSITION, SET_COOKIE}, HeaderName, StatusCode
+    }, response::{Html, IntoResponse, Redirect, Response}, Json
 };
+use chrono::{TimeDelta, Utc};
 use rustrict::Censor;
 use sqlx::PgPool;
 use std::{net::IpAddr, time::Duration};
-use tokio::{sync::mpsc::Sender, task, time::sleep};
+use tokio::{
+    sync::{mpsc::Sender, oneshot, Mutex}, task, time::sleep
+};
+use tracing::{debug, warn};
+use uuid::Uuid;
 
 #[derive(Template)]
 #[template(path = "user-messages.askama.html")]
 pub struct UserMessagesPageTemplate {
     messages: Vec<StandardMessage>,
-    user_id_encoded: String
+    emoji: &'static [&'static str],
+    reasons: &'static [&'static str],
+    announcements: Vec<Announcement>,
+    // only set on location boards, which is also when crossposting makes sense
+    board_name: Option<String>,
+    max_message_length: usize
 }
 
+#[derive(Template)]
+#[template(path = "link-device.askama.html")]
+pub struct LinkDevicePageTemplate;
+
 pub async fn user_referred_index(
-    State(AppState { pool, tx }): State<AppState>,
+    State(AppState { pool, tx, hash_key, .. }): State<AppState>,
     Path(referral_code): Path<String>,
     maybe_local_user_id: MaybeLocalUserId,
     OptionalExtractor(maybe_user): OptionalExtractor<User>,
     ClientIp(ip): ClientIp,
-    maybe_user_agent: MaybeUserAgent
+    user_agent: MaybeUserAgent
 ) -> WR<Response> {
     match maybe_user {
         Some(user) => handle_existing_user(&pool, tx, user, referral_code).await,
         None => {
-            handle_new_user(&pool, maybe_local_user_id, ip, maybe_user_agent, referral_code).await
+            handle_new_user(&pool, &hash_key, maybe_local_user_id, ip, user_agent, referral_code)
+                .await
         }
     }
     .map_err(Into::into)
 }
 
 pub async fn location_referred_index(
-    State(AppState { pool, .. }): State<AppState>,
+    State(AppState { pool, hash_key, .. }): State<AppState>,
     Path(location_code): Path<String>,
     maybe_local_user_id: MaybeLocalUserId,
     OptionalExtractor(maybe_user): OptionalExtractor<User>,
@@ -63,20 +80,33 @@ pub async fn location_referred_index(
     .await?;
 
     let local_user_id = maybe_local_user_id.make();
-    let new_user_code = generate_code();
-
-    let user = sqlx::query_as!(
-        User,
-        "INSERT INTO users (id, code, location_referral, ip, user_agent)
-         VALUES ($1, $2, $3, $4, $5)
-         RETURNING *",
-        local_user_id,
-        new_user_code,
-        found_location_code,
-        ip.to_string(),
-        maybe_user_agent.as_deref()
-    )
-    .fetch_one(&pool)
+    let (pool, ip) = (&pool, &ip.to_string());
+    let ip_hash = &hash_key.hash(ip);
+    let user_agent_hash = &maybe_user_agent.as_deref().map(|user_agent| hash_key.hash(user_agent));
+
+    let user = claim_code(config().invites.code_words, |code| {
+        let (location, user_agent) = (&found_location_code, maybe_user_agent.as_deref());
+        async move {
+            sqlx::query_as!(
+                User,
+                // language=postgresql
+                "INSERT INTO users (id, code, location_referral, ip, user_agent, ip_hash, user_agent_hash)
+                 SELECT $1, $2, $3, $4, $5, $6, $7
+                 WHERE NOT EXISTS (SELECT 1 FROM invites WHERE code = $2)
+                 ON CONFLICT (code) DO NOTHING
+                 RETURNING *",
+                local_user_id,
+                code,
+                location,
+                ip,
+                user_agent,
+                ip_hash,
+                user_agent_hash.as_deref()
+            )
+            .fetch_optional(pool)
+            .await
+        }
+    })
     .await?;
 
     Ok(inject_uuid_cookie(user.user_referral_redirect(), &user))
@@ -93,39 +123,30 @@ async fn handle_existing_user(
     }
 
     if !user.admin {
-        let mut messages = sqlx::query_as!(
-            StandardMessage,
-            // language=postgresql
-            "SELECT id, content, created_at, author FROM messages
-                                   WHERE (published OR author = $1)
-                                   ORDER BY created_at DESC LIMIT 50",
-            user.id
-        )
-        .fetch_all(pool)
-        .await?;
-
-        messages.reverse();
-
-        let page_template =
-            UserMessagesPageTemplate { messages, user_id_encoded: user.encoded_id() };
+        let board = user.lineage(pool).await?.board;
+        let History { messages, .. } = user_history(pool, &user, board, &Cursor::latest()).await?;
+
+        let board_name = if board == GLOBAL_BOARD { None } else { board_name(pool, board).await? };
+
+        let page_template = UserMessagesPageTemplate {
+            messages,
+            emoji: &EMOJI,
+            reasons: &REPORT_REASONS,
+            announcements: active_announcements(pool, Some(board)).await?,
+            board_name,
+            max_message_length: config().messages.max_length
+        };
 
         return Ok(inject_uuid_cookie(MinifiedHtml(page_template), &user));
     }
 
-    let mut messages = sqlx::query_as!(
-        FullMessage,
-        // language=postgresql
-        "SELECT * FROM messages ORDER BY created_at DESC LIMIT 300"
-    )
-    .fetch_all(pool)
-    .await?;
-
-    messages.reverse();
+    let History { messages, .. } = admin_history(pool, None, &Cursor::latest()).await?;
+    let announcements = active_announcements(pool, None).await?;
 
     // this is so dumb but askama gets confused with vue templating syntax and fails to compile
     let admin_page = include_str!("../templates/admin-messages.vue")
         .replace("'{{ MESSAGES }}'", &serde_json::to_string(&messages)?)
-        .replace("'{{ USER_ID }}'", &user.id.to_string())
+        .replace("'{{ ANNOUNCEMENTS }}'", &serde_json::to_string(&announcements)?)
         .replace("'{{ VUE_GLOBAL_SCRIPT }}'", include_str!("../assets/vue.global.prod.js"))
         .replace("'{{ TAILWIND_STYLES }}'", include_str!("../assets/ts.css"));
 
@@ -141,101 +162,471 @@ async fn handle_existing_user(
 
 async fn handle_new_user(
     pool: &PgPool,
+    hash_key: &HashKey,
     maybe_local_user_id: MaybeLocalUserId,
     ip: IpAddr,
     MaybeUserAgent(maybe_user_agent): MaybeUserAgent,
     referral_code: String
 ) -> anyhow::Result<Response> {
-    let referrer_user =
-        sqlx::query_as!(User, "SELECT * FROM users WHERE code = $1 LIMIT 1", &referral_code)
-            .fetch_one(pool)
-            .await?;
+    // the referrer stays locked until the new user is in, see `redeem`
+    let mut transaction = pool.begin().await?;
+
+    // unknown, used up, frozen and over quota all look the same from outside
+    let referrer_user = redeem(&mut transaction, &referral_code.to_lowercase())
+        .await?
+        .ok_or_else(|| anyhow!("no one can join through {referral_code}"))?;
 
     let local_user_id = maybe_local_user_id.make();
-    let new_user_code = generate_code();
-
-    let user = sqlx::query_as!(
-        User,
-        "INSERT INTO users (id, code, user_referral, ip, user_agent, banned)
-         VALUES ($1, $2, $3, $4, $5, $6)
-         RETURNING *",
-        local_user_id,
-        new_user_code,
-        referrer_user.id,
-        ip.to_string(),
-        maybe_user_agent.as_deref(),
-        referrer_user.banned
-    )
-    .fetch_one(pool)
+    let ip = &ip.to_string();
+    let ip_hash = &hash_key.hash(ip);
+    let user_agent_hash = &maybe_user_agent.as_deref().map(|user_agent| hash_key.hash(user_agent));
+
+    let transaction = Mutex::new(transaction);
+    let user = claim_code(config().invites.code_words, |code| {
+        let (referrer, user_agent) = (&referrer_user, maybe_user_agent.as_deref());
+        let transaction = &transaction;
+        async move {
+            sqlx::query_as!(
+                User,
+                // language=postgresql
+                "INSERT INTO users (id, code, user_referral, ip, user_agent, banned, ip_hash, user_agent_hash)
+                 SELECT $1, $2, $3, $4, $5, $6, $7, $8
+                 WHERE NOT EXISTS (SELECT 1 FROM invites WHERE code = $2)
+                 ON CONFLICT (code) DO NOTHING
+                 RETURNING *",
+                local_user_id,
+                code,
+                referrer.id,
+                ip,
+                user_agent,
+                referrer.banned,
+                ip_hash,
+                user_agent_hash.as_deref()
+            )
+            .fetch_optional(&mut **transaction.lock().await)
+            .await
+        }
+    })
     .await?;
 
+    transaction.into_inner().commit().await?;
+
     Ok(inject_uuid_cookie(user.user_referral_redirect(), &user))
 }
 
+/// Top level posts from event stream clients, disguised as a favicon request for networks that
+/// look too closely at anything else. Acknowledged down the stream like socket submissions.
 pub async fn create_message(
-    State(AppState { pool, tx }): State<AppState>,
+    State(AppState { pool, tx, replay, .. }): State<AppState>,
     user: User,
-    OptionalExtractor(header_content): OptionalExtractor<MessageAndIvFromHeaders>
+    OptionalExtractor(header_content): OptionalExtractor<EnvelopeFromHeaders>
 ) -> StatusCode {
     task::spawn(async move {
-        let Some(MessageAndIvFromHeaders(encrypted_content_bytes, iv)) = header_content else {
+        let Some(envelope) = header_content else {
             return;
         };
 
-        let decryptor = Decryptor::<aes::Aes128>::new(
-            user.encryption_key().as_slice().into(),
-            iv.as_slice().into()
-        );
+        // stale or replayed submissions never get as far as decryption
+        if !replay.is_fresh(user.id, &envelope) {
+            debug!("rejected stale or replayed submission from {}", user.id);
+            return;
+        }
+
+        // only a connection of theirs that finished the key exchange can vouch for it
+        let (reply, cipher) = oneshot::channel();
+        let connection = envelope.connection;
+        let request = WebsocketActorMessage::EnvelopeKey { connection, owner: user.id, reply };
+        if tx.send(request).await.is_err() {
+            return;
+        }
 
-        // first & last 8 bytes are noise
-        let Some(cleaned_encrypted_content_bytes) =
-            encrypted_content_bytes.get(8..encrypted_content_bytes.len() - 8)
+        let Some(plaintext) = cipher.await.ok().and_then(|cipher| open_envelope(&cipher, &envelope))
         else {
             return;
         };
 
-        let Ok(decrypted_content) =
-            decryptor.decrypt_padded_vec_mut::<Pkcs7>(cleaned_encrypted_content_bytes)
-        else {
+        // only authenticated envelopes are remembered, and two racing copies can't both get through
+        if !replay.record(user.id, &envelope) {
             return;
-        };
+        }
 
-        let Ok(unclean_content) = String::from_utf8(decrypted_content) else {
+        let Some(submission) = Submission::parse(1, &plaintext) else {
             return;
         };
 
-        let content = clean(&unclean_content);
-        if content.is_empty() || (!user.admin && content.len() > 320) {
-            return;
+        let board = match user.lineage(&pool).await {
+            Ok(lineage) => lineage.board,
+            Err(why) => {
+                warn!("failed to resolve board for {}: {why:?}", user.id);
+                return;
+            }
+        };
+
+        handle_submission(pool, tx.downgrade(), connection, user, board, submission).await;
+    });
+
+    StatusCode::NOT_FOUND
+}
+
+/// Older top level messages. Admins get them as JSON, everyone else gets a sealed frame down the
+/// connection they name, like everything else on their board.
+pub async fn get_history(
+    State(AppState { pool, tx, .. }): State<AppState>,
+    user: User,
+    Query(cursor): Query<Cursor>,
+    Query(BoardFilter { board }): Query<BoardFilter>,
+    Query(HistoryConnection { connection }): Query<HistoryConnection>
+) -> WR<Response> {
+    if user.admin {
+        return Ok(Json(admin_history(&pool, board, &cursor).await?).into_response());
+    }
+
+    let connection = connection.ok_or_else(|| anyhow!("history is only sent down a connection"))?;
+    let board = user.lineage(&pool).await?.board;
+    let history = user_history(&pool, &user, board, &cursor).await?;
+
+    let (reply, sent) = oneshot::channel();
+    tx.send(WebsocketActorMessage::History { connection, owner: user.id, history, reply })
+        .await
+        .map_err(|_| anyhow!("websocket actor is gone"))?;
+    sent.await.map_err(|_| anyhow!("connection {connection} has no session to seal history for"))?;
+
+    Ok(StatusCode::ACCEPTED.into_response())
+}
+
+pub async fn get_thread(
+    State(AppState { pool, .. }): State<AppState>,
+    user: User,
+    Path(id): Path<Uuid>
+) -> WR<Json<Option<Thread>>> {
+    let board = user.lineage(&pool).await?.board;
+
+    // same visibility as the board itself: published, your own, or everything for admins
+    let Some(message) = sqlx::query_as!(
+        StandardMessage,
+        // language=postgresql
+        r#"SELECT id, content, created_at, author,
+                  (SELECT count(*) FROM messages replies
+                   WHERE replies.parent_id = messages.id
+                     AND (replies.published OR replies.author = $2 OR $3)) AS "reply_count!",
+                  ARRAY(SELECT (SELECT count(*) FROM reactions JOIN users ON users.id = reactions.author
+                                WHERE reactions.message = messages.id AND reactions.emoji = emoji.text
+                                  AND (NOT users.banned OR users.id = $2))
+                        FROM unnest($4::text[]) WITH ORDINALITY AS emoji(text, position)
+                        ORDER BY emoji.position) AS "reactions!"
+           FROM messages
+           WHERE id = $1 AND parent_id IS NULL AND (published OR author = $2 OR $3)
+             AND (board_id = $5 OR (crossposted AND $5 = $6) OR $3)
+           LIMIT 1"#,
+        id,
+        user.id,
+        user.admin,
+        &EMOJI.map(String::from),
+        board,
+        GLOBAL_BOARD
+    )
+    .fetch_optional(&pool)
+    .await?
+    else {
+        return Ok(Json(None));
+    };
+
+    let replies = sqlx::query_as!(
+        StandardMessage,
+        // language=postgresql
+        r#"SELECT id, content, created_at, author, 0::int8 AS "reply_count!",
+                  ARRAY(SELECT (SELECT count(*) FROM reactions JOIN users ON users.id = reactions.author
+                                WHERE reactions.message = messages.id AND reactions.emoji = emoji.text
+                                  AND (NOT users.banned OR users.id = $2))
+                        FROM unnest($4::text[]) WITH ORDINALITY AS emoji(text, position)
+                        ORDER BY emoji.position) AS "reactions!"
+           FROM messages
+           WHERE parent_id = $1 AND (published OR author = $2 OR $3)
+           ORDER BY created_at"#,
+        id,
+        user.id,
+        user.admin,
+        &EMOJI.map(String::from)
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    Ok(Json(Some(Thread { message, replies })))
+}
+
+pub async fn create_report(
+    State(AppState { pool, tx, .. }): State<AppState>,
+    user: User,
+    Json(report): Json<NewReport>
+) -> WR<StatusCode> {
+    let lineage = user.lineage(&pool).await?;
+
+    // reporting the same message twice is quietly accepted, it just doesn't count again
+    Ok(match file_report(&pool, &user, lineage.branch, lineage.board, &report).await? {
+        ReportOutcome::Filed(hidden) => {
+            if let Some(message) = hidden {
+                let _ = tx.send(WebsocketActorMessage::Message { message, is_update: true }).await;
+            }
+
+            StatusCode::NO_CONTENT
         }
+        ReportOutcome::Duplicate => StatusCode::NO_CONTENT,
+        ReportOutcome::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+        ReportOutcome::NotFound => StatusCode::NOT_FOUND,
+        ReportOutcome::Invalid => StatusCode::BAD_REQUEST
+    })
+}
 
-        let profanity_type = Censor::from_str(&content).analyze();
-        let score = score_content(profanity_type);
+pub async fn get_invites(
+    State(AppState { pool, .. }): State<AppState>,
+    user: User
+) -> WR<Json<InviteStatus>> {
+    Ok(Json(invite_status(&pool, &user).await?))
+}
 
-        let published = match censor::censor(&pool, &user, &content, score, profanity_type).await {
-            CensorOutcome::Allow => true,
-            CensorOutcome::Hide => false,
-            CensorOutcome::Block => return
-        };
+pub async fn add_invite(
+    State(AppState { pool, .. }): State<AppState>,
+    user: User,
+    Json(new_invite): Json<NewInvite>
+) -> WR<Response> {
+    let Some(invite) = create_invite(&pool, &user, &new_invite).await? else {
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    };
+
+    Ok(Json::<Invite>(invite).into_response())
+}
+
+pub async fn take_back_invite(
+    State(AppState { pool, .. }): State<AppState>,
+    user: User,
+    Path(code): Path<String>
+) -> WR<StatusCode> {
+    Ok(if revoke_invite(&pool, user.id, &code).await? {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    })
+}
+
+/// Gives `user` a new code, which their page moves to.
+pub async fn rotate_user_code(
+    State(AppState { pool, .. }): State<AppState>,
+    user: User
+) -> WR<Json<String>> {
+    Ok(Json(rotate_code(&pool, user.id).await?))
+}
+
+/// Shown once, only a hash of it is kept.
+pub async fn add_recovery_phrase(
+    State(AppState { pool, .. }): State<AppState>,
+    user: User
+) -> WR<Json<String>> {
+    Ok(Json(create_phrase(&pool, user.id).await?))
+}
+
+pub async fn take_back_recovery_phrase(
+    State(AppState { pool, .. }): State<AppState>,
+    user: User
+) -> WR<StatusCode> {
+    Ok(if remove_phrase(&pool, user.id).await? {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    })
+}
+
+/// Signs this device in as whoever the phrase belongs to, answering with their code.
+pub async fn recover_user(
+    State(AppState { pool, .. }): State<AppState>,
+    Json(recovery): Json<Recovery>
+) -> WR<Response> {
+    let Some(user) = recover(&pool, &recovery.phrase).await? else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+
+    Ok(inject_uuid_cookie(Json(&user.code), &user))
+}
+
+/// Only offered with `server.public_origin` set, the Host header is the client's to make up.
+pub async fn add_device_link(
+    State(AppState { pool, .. }): State<AppState>,
+    user: User
+) -> WR<Response> {
+    let Some(origin) = &config().server.public_origin else {
+        return Ok(StatusCode::SERVICE_UNAVAILABLE.into_response());
+    };
+
+    Ok(Json(create_link(&pool, user.id, origin).await?).into_response())
+}
+
+/// Where a scanned link lands. Only asks, since link previews fetch it too and would use it up.
+pub async fn link_device_page() -> MinifiedHtml<LinkDevicePageTemplate> {
+    MinifiedHtml(LinkDevicePageTemplate)
+}
+
+/// The confirmation from that page. The fragment tells the page to forget whoever this device was.
+pub async fn link_device(
+    State(AppState { pool, .. }): State<AppState>,
+    Path(token): Path<String>
+) -> WR<Response> {
+    let user =
+        redeem_link(&pool, &token).await?.ok_or_else(|| anyhow!("device link is no longer valid"))?;
 
-        let full_message = sqlx::query_as!(
-            FullMessage,
+    let redirect = Redirect::to(&format!("/u/{}#linked", user.code));
+    Ok(inject_uuid_cookie(redirect, &user))
+}
+
+/// Everything we hold about the user, as a download.
+pub async fn download_user_export(
+    State(AppState { pool, .. }): State<AppState>,
+    user: User
+) -> WR<([(HeaderName, &'static str); 1], Json<UserExport>)> {
+    let export = export_user(&pool, user).await?;
+    Ok(([(CONTENT_DISPOSITION, "attachment; filename=\"export.json\"")], Json(export)))
+}
+
+/// Deletes the user and everything they wrote, then forgets this device was ever them.
+pub async fn delete_own_user(
+    State(AppState { pool, tx, .. }): State<AppState>,
+    user: User,
+    Json(confirmation): Json<DeleteConfirmation>
+) -> WR<Response> {
+    // an admin leaving would take their moderation history with them, that's done by hand
+    if user.admin {
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    }
+
+    if confirmation.code != user.code {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    }
+
+    let Deletion { messages, reactions } =
+        delete_user(&pool, &user, config().accounts.deleted_user_referrals).await?;
+
+    let _ =
+        tx.send(WebsocketActorMessage::UserDeleted { user: user.id, messages, reactions }).await;
+
+    Ok(([(SET_COOKIE, "__cf=; Path=/; Max-Age=0")], StatusCode::NO_CONTENT).into_response())
+}
+
+/// Opens a header submission, failing if the ciphertext or its timestamp were tampered with.
+pub fn open_envelope(cipher: &Aes256Gcm, envelope: &EnvelopeFromHeaders) -> Option<Vec<u8>> {
+    cipher
+        .decrypt(
+            &envelope.nonce.into(),
+            Payload { msg: &envelope.ciphertext, aad: &envelope.sent_at.to_be_bytes() }
+        )
+        .ok()
+}
+
+#[derive(Debug)]
+pub enum PostOutcome {
+    Accepted(Uuid),
+    Held(Uuid),
+    Blocked,
+    Error(&'static str)
+}
+
+/// What someone asked to post, before it has been cleaned and censored.
+pub struct NewMessage<'a> {
+    pub content: &'a str,
+    pub parent: Option<Uuid>,
+    pub expires_in: Option<TimeDelta>,
+    // the board the author reads, see `User::lineage`
+    pub board: Uuid,
+    // also show it on the global board, meaningless when already posting there
+    pub crosspost: bool
+}
+
+pub async fn post_message(
+    pool: &PgPool,
+    tx: &Sender<WebsocketActorMessage>,
+    user: &User,
+    new_message: NewMessage<'_>
+) -> PostOutcome {
+    let NewMessage { content: unclean_content, parent, expires_in, mut board, mut crosspost } =
+        new_message;
+
+    let content = clean(unclean_content);
+    if content.is_empty() {
+        return PostOutcome::Error("empty");
+    }
+
+    if !user.admin && content.len() > config().messages.max_length {
+        return PostOutcome::Error("too long");
+    }
+
+    // replies only go one level deep, onto messages the author can actually see,
+    // and live wherever their parent does
+    if let Some(parent) = parent {
+        let parent_placement = sqlx::query!(
             // language=postgresql
-            "INSERT INTO messages (content, author, published, score)
-             VALUES ($1, $2, $3, $4) RETURNING *",
-            content,
+            "SELECT board_id, crossposted FROM messages
+             WHERE id = $1 AND parent_id IS NULL
+               AND (published OR author = $2 OR $3)
+               AND (board_id = $4 OR (crossposted AND $4 = $5) OR $3)
+             LIMIT 1",
+            parent,
             user.id,
-            published,
-            score
+            user.admin,
+            board,
+            GLOBAL_BOARD
         )
-        .fetch_one(&pool)
-        .await
-        .expect("failed to insert message");
+        .fetch_optional(pool)
+        .await;
+
+        match parent_placement {
+            Ok(Some(placement)) => {
+                board = placement.board_id;
+                crosspost = placement.crossposted;
+            }
+            Ok(None) => return PostOutcome::Error("no such message"),
+            Err(why) => {
+                warn!("failed to look up reply parent: {why:?}");
+                return PostOutcome::Error("failed to save");
+            }
+        }
+    }
 
-        tx.send(WebsocketActorMessage::Message { message: full_message, is_update: false })
-            .await
-            .expect("failed to send message");
-    });
+    let profanity_type = Censor::from_str(&content).analyze();
+    let score = score_content(profanity_type);
 
-    StatusCode::NOT_FOUND
+    let published = match censor::censor(pool, user, &content, score, profanity_type).await {
+        CensorOutcome::Allow => true,
+        CensorOutcome::Hide => false,
+        CensorOutcome::Block => return PostOutcome::Blocked
+    };
+
+    let full_message = match sqlx::query_as!(
+        FullMessage,
+        // language=postgresql
+        "INSERT INTO messages (content, author, published, score, parent_id, expires_at, board_id, crossposted)
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8) RETURNING *",
+        content,
+        user.id,
+        published,
+        score,
+        parent,
+        expires_in.map(|ttl| Utc::now() + ttl),
+        board,
+        crosspost && board != GLOBAL_BOARD
+    )
+    .fetch_one(pool)
+    .await
+    {
+        Ok(full_message) => full_message,
+        Err(why) => {
+            warn!("failed to insert message: {why:?}");
+            return PostOutcome::Error("failed to save");
+        }
+    };
+
+    let id = full_message.id;
+    let _ = tx.send(WebsocketActorMessage::Message { message: full_message, is_update: false }).await;
+
+    if published {
+        PostOutcome::Accepted(id)
+    } else {
+        PostOutcome::Held(id)
+    }
 }
@@ -0,0 +1,123 @@
+use crate::{
+    config::{config, RetentionConfig}, ws::WebsocketActorMessage
+};
+use chrono::TimeDelta;
+use sqlx::PgPool;
+use std::time::Duration;
+use tokio::{
+    sync::mpsc::Sender, time::{interval, MissedTickBehavior}
+};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// Lifetimes a poster can choose from, sent as an index. The first keeps the message
+/// around until board retention gets to it.
+pub const TTL_CHOICES: [Option<TimeDelta>; 4] =
+    [None, Some(TimeDelta::hours(1)), Some(TimeDelta::days(1)), Some(TimeDelta::weeks(1))];
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+// keeps a backlog (say, retention being switched on) from flooding sockets all at once
+const SWEEP_BATCH: i64 = 500;
+
+pub async fn expiry_task(pool: PgPool, tx: Sender<WebsocketActorMessage>) {
+    let retention = &config().retention;
+
+    let mut sweep_interval = interval(SWEEP_INTERVAL);
+    sweep_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        sweep_interval.tick().await;
+
+        match sweep(&pool, retention).await {
+            Ok((deleted, unpublished)) => {
+                if deleted.is_empty() && unpublished.is_empty() {
+                    continue;
+                }
+
+                info!("expired {} messages, unpublished {}", deleted.len(), unpublished.len());
+
+                if tx.send(WebsocketActorMessage::Expired { deleted, unpublished }).await.is_err() {
+                    break;
+                }
+            }
+            Err(why) => warn!("failed to sweep expired messages: {why:?}")
+        }
+    }
+}
+
+/// Returns the ids of deleted and unpublished messages.
+async fn sweep(
+    pool: &PgPool,
+    retention: &RetentionConfig
+) -> sqlx::Result<(Vec<Uuid>, Vec<Uuid>)> {
+    let mut deleted = sqlx::query_scalar!(
+        // language=postgresql
+        "DELETE FROM messages
+         WHERE id IN (SELECT id FROM messages WHERE expires_at <= now() LIMIT $1)
+         RETURNING id",
+        SWEEP_BATCH
+    )
+    .fetch_all(pool)
+    .await?;
+
+    // announcements only ever expire by their own clock, retention leaves them alone
+    deleted.extend(
+        sqlx::query_scalar!(
+            // language=postgresql
+            "DELETE FROM announcements WHERE expires_at <= now() RETURNING id"
+        )
+        .fetch_all(pool)
+        .await?
+    );
+
+    // dead invites only take up room in the code space
+    sqlx::query!(
+        // language=postgresql
+        "DELETE FROM invites WHERE expires_at <= now() OR uses_left = 0"
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query!(
+        // language=postgresql
+        "DELETE FROM device_links WHERE expires_at <= now()"
+    )
+    .execute(pool)
+    .await?;
+
+    if retention.delete_days > 0 {
+        deleted.extend(
+            sqlx::query_scalar!(
+                // language=postgresql
+                "DELETE FROM messages
+                 WHERE id IN (SELECT id FROM messages
+                              WHERE created_at < now() - make_interval(days => $1)
+                              LIMIT $2)
+                 RETURNING id",
+                retention.delete_days,
+                SWEEP_BATCH
+            )
+            .fetch_all(pool)
+            .await?
+        );
+    }
+
+    let unpublished = if retention.unpublish_days > 0 {
+        sqlx::query_scalar!(
+            // language=postgresql
+            "UPDATE messages SET published = FALSE
+             WHERE id IN (SELECT id FROM messages
+                          WHERE published AND created_at < now() - make_interval(days => $1)
+                          LIMIT $2)
+             RETURNING id",
+            retention.unpublish_days,
+            SWEEP_BATCH
+        )
+        .fetch_all(pool)
+        .await?
+    } else {
+        Vec::new()
+    };
+
+    Ok((deleted, unpublished))
+}
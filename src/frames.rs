@@ -0,0 +1,208 @@
+use crate::{
+    announcements::Announcement, controller::PostOutcome, history::History, messages::{FullMessage, StandardMessage}, session::Session
+};
+use serde::Serialize;
+use std::io;
+use tokio_util::{
+    bytes::{BufMut, Bytes, BytesMut}, codec::Encoder
+};
+use uuid::Uuid;
+
+/// Seals frames for one connection. Each frame is `[kind u8][counter u64][ciphertext + tag]`,
+/// where the plaintext is a run of `[length u32][utf8]` fields.
+pub struct MessageEncoder<'a> {
+    session: &'a mut Session
+}
+
+impl<'a> MessageEncoder<'a> {
+    pub const fn new(session: &'a mut Session) -> Self {
+        Self { session }
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn put_sealed(
+        &mut self,
+        kind: u8,
+        fields: &[&dyn ToString],
+        dst: &mut BytesMut
+    ) -> Result<(), io::Error> {
+        let mut plaintext = BytesMut::new();
+        for field in fields {
+            let field = field.to_string();
+
+            plaintext.put_u32(field.len() as u32);
+            plaintext.extend_from_slice(field.as_bytes());
+        }
+
+        let sealed =
+            self.session.seal(kind, &plaintext).ok_or_else(|| io::Error::other("failed to seal"))?;
+
+        dst.put_u8(kind);
+        dst.extend_from_slice(&sealed);
+
+        Ok(())
+    }
+}
+
+impl Encoder<&FullMessage> for MessageEncoder<'_> {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: &FullMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.put_sealed(0, &[&item.id, &item.content, &item.created_at, &item.author], dst)
+    }
+}
+
+pub struct DeleteMessage(pub Uuid);
+
+impl Encoder<&DeleteMessage> for MessageEncoder<'_> {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: &DeleteMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.put_sealed(1, &[&item.0], dst)
+    }
+}
+
+pub struct PresenceCount {
+    pub online: usize,
+    pub nearby: usize
+}
+
+impl Encoder<&PresenceCount> for MessageEncoder<'_> {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: &PresenceCount, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.put_sealed(2, &[&item.online, &item.nearby], dst)
+    }
+}
+
+pub struct TypingCount(pub usize);
+
+impl Encoder<&TypingCount> for MessageEncoder<'_> {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: &TypingCount, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.put_sealed(3, &[&item.0], dst)
+    }
+}
+
+#[derive(Serialize)]
+pub struct Ack {
+    request: u32,
+    outcome: &'static str,
+    reason: &'static str,
+    id: Option<Uuid>
+}
+
+impl Ack {
+    pub const fn new(request: u32, outcome: &PostOutcome) -> Self {
+        let (outcome, reason, id) = match *outcome {
+            PostOutcome::Accepted(id) => ("accepted", "", Some(id)),
+            PostOutcome::Held(id) => ("held", "", Some(id)),
+            PostOutcome::Blocked => ("blocked", "", None),
+            PostOutcome::Error(reason) => ("error", reason, None)
+        };
+
+        Self { request, outcome, reason, id }
+    }
+}
+
+impl Encoder<&Ack> for MessageEncoder<'_> {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: &Ack, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let id = item.id.map(|id| id.to_string()).unwrap_or_default();
+
+        self.put_sealed(4, &[&item.request, &item.outcome, &item.reason, &id], dst)
+    }
+}
+
+pub struct Reply<'a> {
+    pub message: &'a FullMessage,
+    pub parent: Uuid
+}
+
+impl Encoder<&Reply<'_>> for MessageEncoder<'_> {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: &Reply<'_>, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let message = item.message;
+
+        self.put_sealed(
+            6,
+            &[&message.id, &item.parent, &message.content, &message.created_at, &message.author],
+            dst
+        )
+    }
+}
+
+pub struct ReactionTally {
+    pub message: Uuid,
+    pub emoji: u8,
+    pub count: i64
+}
+
+impl Encoder<&ReactionTally> for MessageEncoder<'_> {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: &ReactionTally, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.put_sealed(7, &[&item.message, &item.emoji, &item.count], dst)
+    }
+}
+
+impl Encoder<&Announcement> for MessageEncoder<'_> {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: &Announcement, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        // taken down with a regular delete frame, the expiry is only there to show
+        let expires_at = item.expires_at.map(|at| at.to_string()).unwrap_or_default();
+
+        self.put_sealed(8, &[&item.id, &item.content, &item.created_at, &expires_at], dst)
+    }
+}
+
+impl Encoder<&History<StandardMessage>> for MessageEncoder<'_> {
+    type Error = io::Error;
+
+    // whether there's more, then six fields a message, oldest first
+    fn encode(
+        &mut self,
+        item: &History<StandardMessage>,
+        dst: &mut BytesMut
+    ) -> Result<(), Self::Error> {
+        let mut fields = vec![item.more.to_string()];
+        for message in &item.messages {
+            let reactions = message.reactions.iter().map(i64::to_string).collect::<Vec<_>>();
+
+            fields.extend([
+                message.id.to_string(),
+                message.content.clone(),
+                // the same form cursors are read back in
+                message.created_at.to_rfc3339(),
+                message.author.to_string(),
+                message.reply_count.to_string(),
+                reactions.join(",")
+            ]);
+        }
+
+        let fields = fields.iter().map(|field| field as &dyn ToString).collect::<Vec<_>>();
+        self.put_sealed(9, &fields, dst)
+    }
+}
+
+/// Opens the key exchange, the only frame that goes out in the clear: `[5][server public 32][connection id 16]`.
+pub struct Handshake {
+    pub public: [u8; 32],
+    pub connection: Uuid
+}
+
+impl Handshake {
+    pub fn frame(&self) -> Bytes {
+        let mut dst = BytesMut::with_capacity(49);
+
+        dst.put_u8(5);
+        dst.extend_from_slice(&self.public);
+        dst.extend_from_slice(self.connection.as_bytes());
+
+        dst.freeze()
+    }
+}
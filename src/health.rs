@@ -0,0 +1,92 @@
+use crate::{config::config, ws::WebsocketActorMessage, AppState};
+use anyhow::{bail, Context};
+use axum::{extract::State, http::StatusCode, Json};
+use serde::Serialize;
+use sqlx::PgPool;
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr}, time::Duration
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream, sync::{mpsc::Sender, oneshot}, time::timeout
+};
+
+// well inside the timeouts orchestrators give probes by default
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize)]
+pub struct Health {
+    pub database: Option<bool>,
+    pub websocket_actor: bool
+}
+
+impl Health {
+    fn status(&self) -> StatusCode {
+        if self.websocket_actor && self.database != Some(false) {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+    }
+}
+
+async fn actor_alive(tx: &Sender<WebsocketActorMessage>) -> bool {
+    let (reply, pong) = oneshot::channel();
+    if tx.send_timeout(WebsocketActorMessage::Ping { reply }, CHECK_TIMEOUT).await.is_err() {
+        return false;
+    }
+
+    matches!(timeout(CHECK_TIMEOUT, pong).await, Ok(Ok(())))
+}
+
+async fn database_reachable(pool: &PgPool) -> bool {
+    let check = sqlx::query_scalar!("SELECT 1 AS \"one!\"").fetch_one(pool);
+    matches!(timeout(CHECK_TIMEOUT, check).await, Ok(Ok(_)))
+}
+
+/// Liveness. Leaves the database out, restarting won't fix that and it would take every
+/// instance down with it.
+pub async fn healthz(State(AppState { tx, .. }): State<AppState>) -> (StatusCode, Json<Health>) {
+    let health = Health { database: None, websocket_actor: actor_alive(&tx).await };
+    (health.status(), Json(health))
+}
+
+/// Readiness, whether this instance can actually serve anyone right now.
+pub async fn readyz(
+    State(AppState { pool, tx, .. }): State<AppState>
+) -> (StatusCode, Json<Health>) {
+    let (database, websocket_actor) = tokio::join!(database_reachable(&pool), actor_alive(&tx));
+
+    let health = Health { database: Some(database), websocket_actor };
+    (health.status(), Json(health))
+}
+
+/// Asks the server running on this machine whether it's ready, for container health checks
+/// where there's no curl to do it.
+pub async fn probe() -> anyhow::Result<()> {
+    let bind = config().server.bind;
+    // listening everywhere includes loopback
+    let ip = match bind.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip
+    };
+
+    let response = timeout(CHECK_TIMEOUT * 2, async {
+        let mut stream = TcpStream::connect(SocketAddr::new(ip, bind.port())).await?;
+        stream.write_all(b"GET /readyz HTTP/1.0\r\nHost: localhost\r\n\r\n").await?;
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        anyhow::Ok(response)
+    })
+    .await
+    .context("server took too long to answer")??;
+
+    let status = response.lines().next().unwrap_or_default();
+    if !status.contains(" 200 ") {
+        bail!("server isn't ready: {status} {}", response.lines().last().unwrap_or_default());
+    }
+
+    println!("{}", response.lines().last().unwrap_or_default());
+    Ok(())
+}
@@ -0,0 +1,159 @@
+use crate::{
+    boards::GLOBAL_BOARD, config::config, messages::{FullMessage, StandardMessage}, reactions::EMOJI, user::User
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// Pages go backwards from here, strictly before this message in `(created_at, id)` order.
+/// Whatever is left out sorts after every message, so no cursor at all is the first page.
+#[derive(Default, Deserialize)]
+pub struct Cursor {
+    pub before: Option<DateTime<Utc>>,
+    pub id: Option<Uuid>
+}
+
+impl Cursor {
+    /// Sorts after every message, so the first page is just the newest ones.
+    pub const fn latest() -> Self {
+        Self { before: None, id: None }
+    }
+
+    fn position(&self) -> (DateTime<Utc>, Uuid) {
+        (self.before.unwrap_or(DateTime::<Utc>::MAX_UTC), self.id.unwrap_or(Uuid::max()))
+    }
+}
+
+/// The connection a user's history is sealed for. Admins are sent plain JSON instead.
+#[derive(Deserialize)]
+pub struct HistoryConnection {
+    pub connection: Option<Uuid>
+}
+
+/// A page of history, oldest first.
+#[derive(Serialize)]
+pub struct History<T> {
+    pub messages: Vec<T>,
+    // whether there is anything before this page
+    pub more: bool
+}
+
+impl<T> History<T> {
+    fn from_newest_first(mut messages: Vec<T>, page_size: i64) -> Self {
+        let page_size = usize::try_from(page_size).unwrap_or(usize::MAX);
+
+        // one extra row is fetched to find out if there is another page
+        let more = messages.len() > page_size;
+        messages.truncate(page_size);
+        messages.reverse();
+
+        Self { messages, more }
+    }
+}
+
+/// Top level messages on `board` that `user` can see: published ones and their own.
+pub async fn user_history(
+    pool: &PgPool,
+    user: &User,
+    board: Uuid,
+    cursor: &Cursor
+) -> sqlx::Result<History<StandardMessage>> {
+    let page_size = config().messages.user_page_size;
+    let (before, id) = cursor.position();
+    let messages = sqlx::query_as!(
+        StandardMessage,
+        // language=postgresql
+        r#"SELECT id, content, created_at, author,
+                  (SELECT count(*) FROM messages replies
+                   WHERE replies.parent_id = messages.id
+                     AND (replies.published OR replies.author = $1)) AS "reply_count!",
+                  ARRAY(SELECT (SELECT count(*) FROM reactions JOIN users ON users.id = reactions.author
+                                WHERE reactions.message = messages.id AND reactions.emoji = emoji.text
+                                  AND (NOT users.banned OR users.id = $1))
+                        FROM unnest($2::text[]) WITH ORDINALITY AS emoji(text, position)
+                        ORDER BY emoji.position) AS "reactions!"
+           FROM messages
+           WHERE parent_id IS NULL AND (published OR author = $1)
+             AND (board_id = $6 OR (crossposted AND $6 = $7))
+             AND (created_at, id) < ($3, $4)
+           ORDER BY created_at DESC, id DESC LIMIT $5"#,
+        user.id,
+        &EMOJI.map(String::from),
+        before,
+        id,
+        page_size + 1,
+        board,
+        GLOBAL_BOARD
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(History::from_newest_first(messages, page_size))
+}
+
+/// Everything, replies and unpublished messages included, optionally only what shows on one board.
+pub async fn admin_history(
+    pool: &PgPool,
+    board: Option<Uuid>,
+    cursor: &Cursor
+) -> sqlx::Result<History<FullMessage>> {
+    let page_size = config().messages.admin_page_size;
+    let (before, id) = cursor.position();
+    let messages = sqlx::query_as!(
+        FullMessage,
+        // language=postgresql
+        "SELECT * FROM messages
+         WHERE (created_at, id) < ($1, $2)
+           AND ($4::uuid IS NULL OR board_id = $4 OR (crossposted AND $4 = $5))
+         ORDER BY created_at DESC, id DESC LIMIT $3",
+        before,
+        id,
+        page_size + 1,
+        board,
+        GLOBAL_BOARD
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(History::from_newest_first(messages, page_size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pages_come_out_oldest_first() {
+        let History { messages, more } = History::from_newest_first(vec![5, 4, 3, 2], 3);
+
+        // the extra row only says there's more, it isn't part of the page
+        assert_eq!(messages, [3, 4, 5]);
+        assert!(more);
+    }
+
+    #[test]
+    fn short_pages_are_the_last() {
+        let History { messages, more } = History::from_newest_first(vec![2, 1], 3);
+        assert_eq!(messages, [1, 2]);
+        assert!(!more);
+
+        let History { messages, more } = History::from_newest_first(vec![3, 2, 1], 3);
+        assert_eq!(messages, [1, 2, 3]);
+        assert!(!more);
+
+        let History { messages, more } = History::<i32>::from_newest_first(Vec::new(), 3);
+        assert!(messages.is_empty());
+        assert!(!more);
+    }
+
+    #[test]
+    fn missing_cursor_parts_sort_after_everything() {
+        let (before, id) = Cursor::latest().position();
+        assert_eq!((before, id), (DateTime::<Utc>::MAX_UTC, Uuid::max()));
+
+        let at = Utc::now();
+        let cursor = Cursor { before: Some(at), id: None };
+        assert_eq!(cursor.position(), (at, Uuid::max()));
+    }
+}
@@ -0,0 +1,243 @@
+use crate::{codes::claim_code, config::config, user::User};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgConnection, PgExecutor, PgPool};
+use uuid::Uuid;
+
+// keeps anyone from minting codes faster than they could ever hand them out
+const MAX_OPEN_INVITES: i64 = 20;
+
+/// A code someone can hand out instead of their own, limited in uses, time or both.
+#[derive(Serialize, FromRow)]
+pub struct Invite {
+    pub code: String,
+    pub owner: Uuid,
+    // null for as many uses as the owner's quota allows
+    pub uses_left: Option<i32>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>
+}
+
+#[derive(Deserialize)]
+pub struct NewInvite {
+    #[serde(default)]
+    pub single_use: bool,
+    #[serde(default)]
+    pub expires_in_hours: Option<i64>
+}
+
+/// What a user can still do with their invites, shown on their page.
+#[derive(Serialize)]
+pub struct InviteStatus {
+    // null for admins, who aren't limited
+    pub invites_left: Option<i64>,
+    pub frozen: bool,
+    pub invites: Vec<Invite>
+}
+
+/// Whether `user` or anyone above them in the referral tree has had their invites frozen.
+pub async fn is_frozen(executor: impl PgExecutor<'_>, user: Uuid) -> sqlx::Result<bool> {
+    sqlx::query_scalar!(
+        // language=postgresql
+        r#"WITH RECURSIVE chain AS (
+            SELECT id, user_referral, invites_frozen FROM users WHERE id = $1
+            UNION ALL
+            SELECT u.id, u.user_referral, u.invites_frozen FROM users u
+            JOIN chain c ON u.id = c.user_referral
+        )
+        SELECT EXISTS (SELECT 1 FROM chain WHERE invites_frozen) AS "frozen!""#,
+        user
+    )
+    .fetch_one(executor)
+    .await
+}
+
+/// How many more people `user` can bring in, nothing if there's no limit.
+pub async fn invites_left(
+    executor: impl PgExecutor<'_>,
+    user: &User
+) -> sqlx::Result<Option<i64>> {
+    if user.admin {
+        return Ok(None);
+    }
+
+    let invited = sqlx::query_scalar!(
+        // language=postgresql
+        r#"SELECT count(*) AS "count!" FROM users WHERE user_referral = $1"#,
+        user.id
+    )
+    .fetch_one(executor)
+    .await?;
+
+    let limit = user.invite_limit.map_or_else(|| config().invites.quota, i64::from);
+
+    Ok(Some((limit - invited).max(0)))
+}
+
+pub async fn invite_status(pool: &PgPool, user: &User) -> sqlx::Result<InviteStatus> {
+    let invites = sqlx::query_as!(
+        Invite,
+        // language=postgresql
+        "SELECT * FROM invites
+         WHERE owner = $1 AND (expires_at IS NULL OR expires_at > now())
+           AND (uses_left IS NULL OR uses_left > 0)
+         ORDER BY created_at DESC",
+        user.id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(InviteStatus {
+        invites_left: invites_left(pool, user).await?,
+        frozen: is_frozen(pool, user.id).await?,
+        invites
+    })
+}
+
+/// Returns nothing if `owner` can't invite anyone right now or already has too many codes out.
+pub async fn create_invite(
+    pool: &PgPool,
+    owner: &User,
+    new_invite: &NewInvite
+) -> anyhow::Result<Option<Invite>> {
+    // banned users still get codes, whoever joins through them is banned along with them
+    if is_frozen(pool, owner.id).await?
+        || invites_left(pool, owner).await?.is_some_and(|left| left == 0)
+    {
+        return Ok(None);
+    }
+
+    let expires_at = new_invite
+        .expires_in_hours
+        .filter(|hours| *hours > 0)
+        .and_then(TimeDelta::try_hours)
+        .map(|expires_in| Utc::now() + expires_in);
+
+    let open = sqlx::query_scalar!(
+        // language=postgresql
+        r#"SELECT count(*) AS "count!" FROM invites
+           WHERE owner = $1 AND (expires_at IS NULL OR expires_at > now())
+             AND (uses_left IS NULL OR uses_left > 0)"#,
+        owner.id
+    )
+    .fetch_one(pool)
+    .await?;
+
+    if open >= MAX_OPEN_INVITES {
+        return Ok(None);
+    }
+
+    // users' own codes share the namespace, so one of theirs is never shadowed
+    claim_code(config().invites.code_words, |code| async move {
+        sqlx::query_as!(
+            Invite,
+            // language=postgresql
+            "INSERT INTO invites (code, owner, uses_left, expires_at)
+             SELECT $1, $2, $3, $4 WHERE NOT EXISTS (SELECT 1 FROM users WHERE code = $1)
+             ON CONFLICT DO NOTHING
+             RETURNING *",
+            code,
+            owner.id,
+            new_invite.single_use.then_some(1),
+            expires_at
+        )
+        .fetch_optional(pool)
+        .await
+    })
+    .await
+    .map(Some)
+}
+
+/// Only the owner can revoke their invites. Returns false otherwise.
+pub async fn revoke_invite(pool: &PgPool, owner: Uuid, code: &str) -> sqlx::Result<bool> {
+    sqlx::query!(
+        // language=postgresql
+        "DELETE FROM invites WHERE code = $1 AND owner = $2",
+        code.to_lowercase(),
+        owner
+    )
+    .execute(pool)
+    .await
+    .map(|result| result.rows_affected() > 0)
+}
+
+/// Swaps `user`'s code for a fresh one, so a leaked link stops working. Everything else
+/// about them stays put, their old page just stops resolving.
+pub async fn rotate_code(pool: &PgPool, user: Uuid) -> anyhow::Result<String> {
+    claim_code(config().invites.code_words, |code| async move {
+        sqlx::query_scalar!(
+            // language=postgresql
+            "UPDATE users SET code = $2
+             WHERE id = $1
+               AND NOT EXISTS (SELECT 1 FROM users WHERE code = $2)
+               AND NOT EXISTS (SELECT 1 FROM invites WHERE code = $2)
+             RETURNING code",
+            user,
+            code
+        )
+        .fetch_optional(pool)
+        .await
+    })
+    .await
+}
+
+/// Resolves `code`, either someone's own or one of their invites, to whoever a new user
+/// joining through it would be invited by. Takes a use off an invite on the way. Returns
+/// nothing if the code is unknown, used up or expired, or its owner can't invite anyone.
+///
+/// Run it in the transaction that inserts the new user. The owner's row stays locked until
+/// that commits, so two people joining at once can't both take their last invite.
+pub async fn redeem(connection: &mut PgConnection, code: &str) -> sqlx::Result<Option<User>> {
+    let own = sqlx::query_as!(User, "SELECT * FROM users WHERE code = $1 FOR UPDATE", code)
+        .fetch_optional(&mut *connection)
+        .await?;
+
+    let owner = match own {
+        Some(owner) => Some(owner),
+        None => {
+            sqlx::query_as!(
+                User,
+                // language=postgresql
+                "SELECT users.* FROM invites JOIN users ON users.id = invites.owner
+                 WHERE invites.code = $1
+                   AND (invites.expires_at IS NULL OR invites.expires_at > now())
+                   AND (invites.uses_left IS NULL OR invites.uses_left > 0)
+                 FOR UPDATE OF users",
+                code
+            )
+            .fetch_optional(&mut *connection)
+            .await?
+        }
+    };
+
+    let Some(owner) = owner else {
+        return Ok(None);
+    };
+
+    if is_frozen(&mut *connection, owner.id).await?
+        || invites_left(&mut *connection, &owner).await?.is_some_and(|left| left == 0)
+    {
+        return Ok(None);
+    }
+
+    if owner.code == code {
+        return Ok(Some(owner));
+    }
+
+    // someone else may have taken the last use in the meantime
+    let taken = sqlx::query!(
+        // language=postgresql
+        "UPDATE invites SET uses_left = uses_left - 1
+         WHERE code = $1 AND (expires_at IS NULL OR expires_at > now())
+           AND (uses_left IS NULL OR uses_left > 0)",
+        code
+    )
+    .execute(&mut *connection)
+    .await?;
+
+    if taken.rows_affected() == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(owner))
+}
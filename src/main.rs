@@ -1,72 +1,209 @@
+mod account;
 mod admin_controller;
+mod announcements;
+mod boards;
 mod censor;
+mod cli;
+mod client_ip;
+mod codes;
+mod config;
 mod controller;
+mod expiry;
+mod frames;
+mod health;
+mod history;
+mod invites;
 mod messages;
+mod presence;
+mod reactions;
+mod recovery;
+mod replay;
+mod reports;
+mod reputation;
+mod retention;
+mod schedule;
+mod search;
+mod session;
+mod typing;
 mod user;
 mod util;
 mod ws;
 
 use crate::{
-    user::{inject_uuid_cookie, User}, util::{OptionalExtractor, WebErrorExtensionMarker}, ws::WebsocketActorMessage
+    cli::Cli, client_ip::IpResolver, config::Config, replay::ReplayCache, retention::HashKey, user::{inject_uuid_cookie, User}, util::{OptionalExtractor, WebErrorExtensionMarker}, ws::WebsocketActorMessage
 };
+use anyhow::Context;
 use axum::{
-    extract::{Request, State}, http::{header::WWW_AUTHENTICATE, HeaderMap, StatusCode}, middleware::{from_fn_with_state, Next}, response::{IntoResponse, Response}, routing::{any, get}, RequestExt, Router
+    extract::{Request, State}, http::{header::WWW_AUTHENTICATE, HeaderMap, StatusCode}, middleware::{from_fn_with_state, Next}, response::{IntoResponse, Response}, routing::{any, delete, get, post}, RequestExt, Router
 };
 use base64::{prelude::BASE64_STANDARD, Engine};
+use clap::Parser;
 use sqlx::{
-    postgres::{PgConnectOptions, PgPoolOptions}, PgPool
+    migrate::Migrator, postgres::{PgConnectOptions, PgPoolOptions}, PgPool
 };
-use tokio::sync::{mpsc, mpsc::Sender};
+use std::{
+    future::{pending, IntoFuture}, io, net::SocketAddr, sync::Arc, time::Duration
+};
+use tokio::{
+    signal::unix::{signal, SignalKind}, sync::{mpsc, mpsc::Sender}, time::sleep
+};
+use tokio_util::sync::CancellationToken;
 use tracing::{info, level_filters::LevelFilter, warn};
 use tracing_subscriber::EnvFilter;
 
 #[derive(Clone)]
 pub struct AppState {
     pool: PgPool,
-    tx: Sender<WebsocketActorMessage>
+    tx: Sender<WebsocketActorMessage>,
+    replay: Arc<ReplayCache>,
+    ip_resolver: Arc<IpResolver>,
+    hash_key: Arc<HashKey>
 }
 
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+// inside the 10 seconds docker waits before killing
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let _ = dotenvy::dotenv();
+    let cli = Cli::parse();
+
+    let config = Config::load(cli.config.as_deref())?;
+    // printed even when it's invalid, that's when you'd want to look at it
+    if cli.print_config {
+        print!("{}", config.redacted()?);
+        if let Err(why) = config.validate() {
+            eprintln!("{why:#}");
+        }
+        return Ok(());
+    }
+
+    config.validate()?;
+    let config = config.install();
 
     let filter = EnvFilter::builder()
         .with_default_directive(LevelFilter::INFO.into())
         .from_env()?
         .add_directive("message_board=debug".parse()?);
 
-    tracing_subscriber::fmt().with_env_filter(filter).compact().init();
+    // stdout is left to commands like export
+    tracing_subscriber::fmt().with_env_filter(filter).with_writer(io::stderr).compact().init();
 
-    let pg_connect_opts = dotenvy::var("DATABASE_URL")?.parse::<PgConnectOptions>()?;
+    let pg_connect_opts = config.database.url.parse::<PgConnectOptions>()?;
     let pool = PgPoolOptions::new().connect_lazy_with(pg_connect_opts.clone());
 
+    match cli.command {
+        Some(command) => cli::run(command, &pool).await,
+        None => serve(config, pool, &pg_connect_opts).await
+    }
+}
+
+async fn serve(
+    config: &'static Config,
+    pool: PgPool,
+    pg_connect_opts: &PgConnectOptions
+) -> anyhow::Result<()> {
     info!("attempting to run migrations with db host {}...", pg_connect_opts.get_host());
-    if let Err(why) = sqlx::migrate!().run(&pool).await {
+    if let Err(why) = MIGRATOR.run(&pool).await {
+        if config.server.strict_startup {
+            return Err(why).context("migrations failed, not serving without a working database");
+        }
         warn!("migrations failed: {why:?}");
     } else {
         info!("migrations ran successfully / db connection valid");
     }
 
-    let (tx, rx) = mpsc::channel(100);
+    let (tx, rx) = mpsc::channel(config.server.channel_capacity);
+    let hash_key = Arc::new(HashKey::new(&config.retention.hash_key)?);
 
-    let state = AppState { pool, tx };
+    let state = AppState {
+        pool: PgPool::clone(&pool),
+        tx: Sender::clone(&tx),
+        replay: Arc::new(ReplayCache::default()),
+        ip_resolver: Arc::new(IpResolver::new(&config.proxy)?),
+        hash_key: Arc::clone(&hash_key)
+    };
 
     let app = Router::new()
         .route("/l/{code}", get(controller::location_referred_index))
         .route("/u/{code}", get(controller::user_referred_index))
         .route("/favicon.ico", get(controller::create_message))
+        .route("/t/{id}", get(controller::get_thread))
+        .route("/h", get(controller::get_history))
+        .route("/r", post(controller::create_report))
+        .route("/i", get(controller::get_invites).post(controller::add_invite))
+        .route("/i/rotate", post(controller::rotate_user_code))
+        .route("/i/{code}", delete(controller::take_back_invite))
+        .route("/k", post(controller::add_recovery_phrase).delete(controller::take_back_recovery_phrase))
+        .route("/k/recover", post(controller::recover_user))
+        .route("/d", post(controller::add_device_link))
+        .route("/d/{token}", get(controller::link_device_page).post(controller::link_device))
+        .route("/e", get(controller::download_user_export).delete(controller::delete_own_user))
         .route("/-", any(ws::ws_route))
+        .route("/_", get(ws::sse_route).post(ws::sse_frame_route))
         .nest("/admin", admin_controller::admin_controller(AppState::clone(&state)))
         .fallback(inner_fallback)
         .method_not_allowed_fallback(inner_fallback)
         .layer(from_fn_with_state(AppState::clone(&state), intercept_web_error))
+        // after the layer, probes shouldn't be redirected or asked to log in
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
         .with_state(state);
 
     #[allow(clippy::let_underscore_future)]
-    let _ = tokio::spawn(ws::socket_owner_actor(rx));
+    let _ = tokio::spawn(ws::socket_owner_actor(rx, PgPool::clone(&pool), tx.downgrade()));
+
+    #[allow(clippy::let_underscore_future)]
+    let _ = tokio::spawn(expiry::expiry_task(PgPool::clone(&pool), Sender::clone(&tx)));
+
+    #[allow(clippy::let_underscore_future)]
+    let _ = tokio::spawn(retention::retention_task(PgPool::clone(&pool), hash_key));
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:5000").await?;
-    axum::serve(listener, app.into_make_service()).await.map_err(Into::into)
+    #[allow(clippy::let_underscore_future)]
+    let _ = tokio::spawn(schedule::scheduler_task(pool, tx));
+
+    let listener = tokio::net::TcpListener::bind(config.server.bind).await?;
+    let shutdown = CancellationToken::new();
+    #[allow(clippy::let_underscore_future)]
+    let _ = tokio::spawn(shutdown_signal(CancellationToken::clone(&shutdown)));
+
+    let server = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(CancellationToken::clone(&shutdown).cancelled_owned());
+
+    // event streams never finish by themselves, so they only get so long
+    tokio::select! {
+        result = server.into_future() => result?,
+        () = async { shutdown.cancelled().await; sleep(SHUTDOWN_GRACE).await } => {
+            warn!("closing connections still open after {SHUTDOWN_GRACE:?}");
+        }
+    }
+
+    Ok(())
+}
+
+// containers are stopped with SIGTERM, which nothing would answer otherwise when running as pid 1
+async fn shutdown_signal(shutdown: CancellationToken) {
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(why) => {
+                warn!("can't listen for SIGTERM: {why:?}");
+                pending::<()>().await;
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        () = terminate => {}
+    }
+
+    info!("shutting down");
+    shutdown.cancel();
 }
 
 async fn inner_fallback(
@@ -76,7 +213,7 @@ async fn inner_fallback(
 ) -> Response {
     if let Some(user) = maybe_user {
         return inject_uuid_cookie(user.user_referral_redirect(), &user);
-    };
+    }
 
     if let Some(login) = headers
         .get("Authorization")
@@ -1,3 +1,4 @@
+use crate::{boards::GLOBAL_BOARD, reactions::EMOJI};
 use chrono::{DateTime, Utc};
 use serde::Serialize;
 use sqlx::FromRow;
@@ -8,7 +9,16 @@ pub struct StandardMessage {
     pub id: Uuid,
     pub content: String,
     pub created_at: DateTime<Utc>,
-    pub author: Uuid
+    pub author: Uuid,
+    pub reply_count: i64,
+    // lined up with `EMOJI`
+    pub reactions: Vec<i64>
+}
+
+impl StandardMessage {
+    pub fn reaction_counts(&self) -> Vec<(usize, &'static str, i64)> {
+        EMOJI.iter().zip(&self.reactions).enumerate().map(|(i, (e, c))| (i, *e, *c)).collect()
+    }
 }
 
 #[derive(Serialize, FromRow, Clone)]
@@ -18,5 +28,23 @@ pub struct FullMessage {
     pub author: Uuid,
     pub published: bool,
     pub score: f32,
-    pub created_at: DateTime<Utc>
+    pub created_at: DateTime<Utc>,
+    pub parent_id: Option<Uuid>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub board_id: Uuid,
+    pub crossposted: bool
+}
+
+impl FullMessage {
+    /// Whether someone reading `board` gets to see this.
+    pub fn shows_on(&self, board: Uuid) -> bool {
+        self.board_id == board || (self.crossposted && board == GLOBAL_BOARD)
+    }
+}
+
+/// A top level message and the replies to it that the viewer is allowed to see.
+#[derive(Serialize)]
+pub struct Thread {
+    pub message: StandardMessage,
+    pub replies: Vec<StandardMessage>
 }
@@ -0,0 +1,149 @@
+use crate::ws::Connection;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque}, time::{Duration, Instant}
+};
+use uuid::Uuid;
+
+// how often counts may be pushed out to connected clients
+const PUSH_INTERVAL: Duration = Duration::from_secs(5);
+// one sample a minute, one day kept around for the admin chart
+const SAMPLE_INTERVAL: Duration = Duration::from_mins(1);
+const MAX_SAMPLES: usize = 1440;
+
+#[derive(Serialize, Clone, Default, PartialEq, Eq)]
+pub struct PresenceSnapshot {
+    pub count: usize,
+    pub locations: BTreeMap<String, usize>,
+    pub branches: BTreeMap<Uuid, usize>
+}
+
+impl PresenceSnapshot {
+    pub fn from_connections(connections: &[Connection]) -> Self {
+        let mut snapshot = Self::default();
+        let mut seen = HashSet::new();
+
+        // users with several tabs open only count once
+        for connection in connections.iter().filter(|c| seen.insert(c.owner.id)) {
+            snapshot.count += 1;
+            *snapshot.branches.entry(connection.lineage.branch).or_default() += 1;
+
+            if let Some(location) = &connection.lineage.location {
+                *snapshot.locations.entry(location.clone()).or_default() += 1;
+            }
+        }
+
+        snapshot
+    }
+
+    pub fn nearby(&self, location: Option<&String>) -> usize {
+        location.and_then(|l| self.locations.get(l)).copied().unwrap_or(self.count)
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct PresenceSample {
+    pub at: DateTime<Utc>,
+    pub count: usize
+}
+
+#[derive(Serialize)]
+pub struct PresenceReport {
+    pub current: PresenceSnapshot,
+    pub series: Vec<PresenceSample>
+}
+
+#[derive(Default)]
+pub struct Presence {
+    samples: VecDeque<PresenceSample>,
+    last_sample: Option<Instant>,
+    last_push: Option<(Instant, PresenceSnapshot)>
+}
+
+impl Presence {
+    pub fn record(&mut self, snapshot: &PresenceSnapshot) {
+        if self.last_sample.is_some_and(|at| at.elapsed() < SAMPLE_INTERVAL) {
+            return;
+        }
+
+        if self.samples.len() >= MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+
+        self.samples.push_back(PresenceSample { at: Utc::now(), count: snapshot.count });
+        self.last_sample = Some(Instant::now());
+    }
+
+    /// Whether `snapshot` differs from what clients last saw and the throttle window has passed.
+    pub fn should_push(&mut self, snapshot: &PresenceSnapshot) -> bool {
+        match &self.last_push {
+            Some((_, last)) if last == snapshot => false,
+            Some((at, _)) if at.elapsed() < PUSH_INTERVAL => false,
+            _ => {
+                self.last_push = Some((Instant::now(), snapshot.clone()));
+                true
+            }
+        }
+    }
+
+    pub fn report(&self, current: PresenceSnapshot) -> PresenceReport {
+        PresenceReport { current, series: self.samples.iter().cloned().collect() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(count: usize) -> PresenceSnapshot {
+        PresenceSnapshot { count, ..PresenceSnapshot::default() }
+    }
+
+    fn long_ago(interval: Duration) -> Instant {
+        Instant::now().checked_sub(interval).unwrap()
+    }
+
+    #[test]
+    fn pushes_changes_at_most_once_a_window() {
+        let mut presence = Presence::default();
+
+        assert!(presence.should_push(&snapshot(1)));
+        assert!(!presence.should_push(&snapshot(1)));
+        // changed, but too soon after the last one
+        assert!(!presence.should_push(&snapshot(2)));
+
+        presence.last_push = Some((long_ago(PUSH_INTERVAL), snapshot(1)));
+        assert!(!presence.should_push(&snapshot(1)));
+        assert!(presence.should_push(&snapshot(2)));
+        assert!(!presence.should_push(&snapshot(3)));
+    }
+
+    #[test]
+    fn samples_once_a_minute_up_to_a_day() {
+        let mut presence = Presence::default();
+
+        presence.record(&snapshot(1));
+        presence.record(&snapshot(2));
+        assert_eq!(presence.samples.len(), 1);
+
+        for count in 0..=MAX_SAMPLES {
+            presence.last_sample = Some(long_ago(SAMPLE_INTERVAL));
+            presence.record(&snapshot(count));
+        }
+
+        let series = presence.report(snapshot(0)).series;
+        assert_eq!(series.len(), MAX_SAMPLES);
+        assert_eq!(series.last().unwrap().count, MAX_SAMPLES);
+    }
+
+    #[test]
+    fn nearby_falls_back_to_everyone() {
+        let mut snapshot = snapshot(5);
+        snapshot.locations.insert("library".to_string(), 2);
+
+        assert_eq!(snapshot.nearby(Some(&"library".to_string())), 2);
+        assert_eq!(snapshot.nearby(Some(&"lab".to_string())), 5);
+        assert_eq!(snapshot.nearby(None), 5);
+    }
+}
@@ -0,0 +1,150 @@
+use crate::{boards::GLOBAL_BOARD, user::User};
+use sqlx::{PgExecutor, PgPool};
+use uuid::Uuid;
+
+/// Everything a message can be reacted with. Clients refer to these by index,
+/// so only ever append to the end.
+pub const EMOJI: [&str; 6] = ["👍", "❤️", "😂", "😮", "😢", "🔥"];
+
+/// The count of one emoji on one message after someone toggled it.
+pub struct ReactionCount {
+    pub message: Uuid,
+    pub emoji: u8,
+    pub author: Uuid,
+    // shadowbanned, so nobody else may even hear that they reacted
+    pub banned: bool,
+    // where the message is and who can see it, so the tally only goes where the message does
+    pub board: Uuid,
+    pub crossposted: bool,
+    pub published: bool,
+    pub message_author: Uuid,
+    // what everyone else sees, banned users' reactions don't count
+    pub count: i64,
+    // what the person who reacted sees, their own reaction always counts for them
+    pub author_count: i64
+}
+
+impl ReactionCount {
+    /// Same rules as the message itself, on `board` and published unless it's the viewer's own.
+    pub fn shows_to(&self, viewer: Uuid, board: Uuid) -> bool {
+        (self.published || self.message_author == viewer)
+            && (self.board == board || (self.crossposted && board == GLOBAL_BOARD))
+    }
+}
+
+/// Adds `user`'s reaction if they haven't made it yet, otherwise takes it back. Returns nothing
+/// when the emoji isn't one we know or the message isn't one `user` can see from `board`.
+pub async fn toggle_reaction(
+    pool: &PgPool,
+    user: &User,
+    board: Uuid,
+    message: Uuid,
+    emoji: u8
+) -> sqlx::Result<Option<ReactionCount>> {
+    let Some(emoji_text) = EMOJI.get(usize::from(emoji)) else {
+        return Ok(None);
+    };
+
+    let Some(target) = sqlx::query!(
+        // language=postgresql
+        "SELECT board_id, crossposted, published, author FROM messages
+         WHERE id = $1 AND (published OR author = $2 OR $3)
+           AND (board_id = $4 OR (crossposted AND $4 = $5) OR $3)",
+        message,
+        user.id,
+        user.admin,
+        board,
+        GLOBAL_BOARD
+    )
+    .fetch_optional(pool)
+    .await?
+    else {
+        return Ok(None);
+    };
+
+    sqlx::query!(
+        // language=postgresql
+        "WITH removed AS (
+             DELETE FROM reactions WHERE message = $1 AND author = $2 AND emoji = $3 RETURNING 1
+         )
+         INSERT INTO reactions (message, author, emoji)
+         SELECT $1, $2, $3 WHERE NOT EXISTS (SELECT 1 FROM removed)
+         ON CONFLICT DO NOTHING",
+        message,
+        user.id,
+        emoji_text
+    )
+    .execute(pool)
+    .await?;
+
+    let counts = sqlx::query!(
+        // language=postgresql
+        r#"SELECT count(*) FILTER (WHERE NOT users.banned) AS "count!",
+                  count(*) FILTER (WHERE NOT users.banned OR users.id = $3) AS "author_count!"
+           FROM reactions JOIN users ON users.id = reactions.author
+           WHERE reactions.message = $1 AND reactions.emoji = $2"#,
+        message,
+        emoji_text,
+        user.id
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(Some(ReactionCount {
+        message,
+        emoji,
+        author: user.id,
+        banned: user.banned,
+        board: target.board_id,
+        crossposted: target.crossposted,
+        published: target.published,
+        message_author: target.author,
+        count: counts.count,
+        author_count: counts.author_count
+    }))
+}
+
+/// Fresh counts for `reactions`, as `(message, emoji)` pairs, after `author` changed them some
+/// other way than toggling. Messages that are gone by now are left out.
+pub async fn recount(
+    executor: impl PgExecutor<'_>,
+    author: Uuid,
+    reactions: &[(Uuid, String)]
+) -> sqlx::Result<Vec<ReactionCount>> {
+    let (messages, emoji): (Vec<_>, Vec<_>) = reactions.iter().cloned().unzip();
+
+    let rows = sqlx::query!(
+        // language=postgresql
+        r#"SELECT messages.id, changed.emoji AS "emoji!", messages.board_id, messages.crossposted,
+                  messages.published, messages.author,
+                  (SELECT count(*) FROM reactions JOIN users ON users.id = reactions.author
+                   WHERE reactions.message = messages.id AND reactions.emoji = changed.emoji
+                     AND NOT users.banned) AS "count!"
+           FROM unnest($1::uuid[], $2::text[]) AS changed(message, emoji)
+           JOIN messages ON messages.id = changed.message"#,
+        &messages,
+        &emoji
+    )
+    .fetch_all(executor)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| {
+            let emoji = EMOJI.iter().position(|known| *known == row.emoji)?;
+
+            Some(ReactionCount {
+                message: row.id,
+                emoji: u8::try_from(emoji).ok()?,
+                author,
+                banned: false,
+                board: row.board_id,
+                crossposted: row.crossposted,
+                published: row.published,
+                message_author: row.author,
+                count: row.count,
+                author_count: row.count
+            })
+        })
+        .collect())
+}
@@ -0,0 +1,120 @@
+use crate::{codes::generate_phrase, config::config, user::User};
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, TimeDelta, Utc};
+use qrcode::{render::svg, QrCode};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+// around 108 bits with the full word list, far beyond guessing
+const PHRASE_WORDS: usize = 6;
+
+fn digest(secret: &str) -> Vec<u8> {
+    Sha256::digest(secret.as_bytes()).to_vec()
+}
+
+// forgiving about case and spacing, people type these back in by hand
+fn normalize(phrase: &str) -> String {
+    phrase.split_whitespace().map(str::to_lowercase).collect::<Vec<_>>().join(" ")
+}
+
+/// Gives `user` a new recovery phrase, replacing any earlier one. Only its hash is kept, so
+/// this is the one chance to show it.
+pub async fn create_phrase(pool: &PgPool, user: Uuid) -> sqlx::Result<String> {
+    let phrase = generate_phrase(PHRASE_WORDS);
+
+    sqlx::query!(
+        // language=postgresql
+        "INSERT INTO recovery_phrases (user_id, phrase_hash) VALUES ($1, $2)
+         ON CONFLICT (user_id) DO UPDATE SET phrase_hash = $2, created_at = now()",
+        user,
+        digest(&phrase)
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(phrase)
+}
+
+/// Returns false if `user` never had one.
+pub async fn remove_phrase(pool: &PgPool, user: Uuid) -> sqlx::Result<bool> {
+    sqlx::query!(
+        // language=postgresql
+        "DELETE FROM recovery_phrases WHERE user_id = $1",
+        user
+    )
+    .execute(pool)
+    .await
+    .map(|result| result.rows_affected() > 0)
+}
+
+#[derive(Deserialize)]
+pub struct Recovery {
+    pub phrase: String
+}
+
+/// Whoever `phrase` belongs to, if anyone.
+pub async fn recover(pool: &PgPool, phrase: &str) -> sqlx::Result<Option<User>> {
+    sqlx::query_as!(
+        User,
+        // language=postgresql
+        "SELECT users.* FROM recovery_phrases JOIN users ON users.id = recovery_phrases.user_id
+         WHERE recovery_phrases.phrase_hash = $1",
+        digest(&normalize(phrase))
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// A one time link that signs another device in as the same user, and the QR code for it.
+#[derive(Serialize)]
+pub struct DeviceLink {
+    pub url: String,
+    pub expires_at: DateTime<Utc>,
+    pub qr: String
+}
+
+/// Replaces any link `user` still had open. `origin` is where the other device should go.
+pub async fn create_link(pool: &PgPool, user: Uuid, origin: &str) -> anyhow::Result<DeviceLink> {
+    let token = BASE64_URL_SAFE_NO_PAD.encode(rand::rng().random::<[u8; 32]>());
+    let expires_at = Utc::now() + TimeDelta::minutes(config().accounts.device_link_minutes);
+
+    sqlx::query!(
+        // language=postgresql
+        "WITH stale AS (DELETE FROM device_links WHERE user_id = $1)
+         INSERT INTO device_links (token_hash, user_id, expires_at) VALUES ($2, $1, $3)",
+        user,
+        digest(&token),
+        expires_at
+    )
+    .execute(pool)
+    .await?;
+
+    let url = format!("{origin}/d/{token}");
+    let qr = QrCode::new(url.as_bytes())?
+        .render::<svg::Color>()
+        .min_dimensions(200, 200)
+        .dark_color(svg::Color("#e4e4e7"))
+        .light_color(svg::Color("#0f172a"))
+        .build();
+
+    Ok(DeviceLink { url, expires_at, qr })
+}
+
+/// Uses up `token`, returning who it signs in as if it was still good.
+pub async fn redeem_link(pool: &PgPool, token: &str) -> sqlx::Result<Option<User>> {
+    sqlx::query_as!(
+        User,
+        // language=postgresql
+        "WITH link AS (
+             DELETE FROM device_links WHERE token_hash = $1 RETURNING user_id, expires_at
+         )
+         SELECT users.* FROM link JOIN users ON users.id = link.user_id
+         WHERE link.expires_at > now()",
+        digest(token)
+    )
+    .fetch_optional(pool)
+    .await
+}
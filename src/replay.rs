@@ -0,0 +1,102 @@
+use crate::util::EnvelopeFromHeaders;
+use chrono::Utc;
+use std::{collections::HashMap, sync::Mutex};
+use uuid::Uuid;
+
+// how far a submission's timestamp may drift from our clock, in milliseconds
+const MAX_SKEW: i64 = 30_000;
+
+/// Remembers the nonces of recently accepted header submissions. An entry only has to outlive
+/// its timestamp window, after that the submission would be rejected as stale anyway.
+#[derive(Default)]
+pub struct ReplayCache {
+    seen: Mutex<HashMap<(Uuid, [u8; 12]), i64>>
+}
+
+impl ReplayCache {
+    /// Cheap check done before decrypting: is the envelope recent and not already used?
+    pub fn is_fresh(&self, user: Uuid, envelope: &EnvelopeFromHeaders) -> bool {
+        self.is_fresh_at(user, envelope, Utc::now().timestamp_millis())
+    }
+
+    /// Records an authenticated envelope, returning false if it was already recorded.
+    pub fn record(&self, user: Uuid, envelope: &EnvelopeFromHeaders) -> bool {
+        self.record_at(user, envelope, Utc::now().timestamp_millis())
+    }
+
+    // the window is open at both ends, an entry is dropped at exactly the moment its envelope
+    // turns stale, so nothing can slip through in between
+    fn is_fresh_at(&self, user: Uuid, envelope: &EnvelopeFromHeaders, now: i64) -> bool {
+        if (now - envelope.sent_at).abs() >= MAX_SKEW {
+            return false;
+        }
+
+        !self.seen.lock().unwrap().contains_key(&(user, envelope.nonce))
+    }
+
+    fn record_at(&self, user: Uuid, envelope: &EnvelopeFromHeaders, now: i64) -> bool {
+        let mut seen = self.seen.lock().unwrap();
+
+        seen.retain(|_, expires_at| *expires_at > now);
+        seen.insert((user, envelope.nonce), envelope.sent_at + MAX_SKEW).is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SENT_AT: i64 = 1_700_000_000_000;
+
+    fn envelope(nonce: u8) -> EnvelopeFromHeaders {
+        EnvelopeFromHeaders {
+            sent_at: SENT_AT,
+            nonce: [nonce; 12],
+            connection: Uuid::nil(),
+            ciphertext: Vec::new()
+        }
+    }
+
+    #[test]
+    fn rejects_stale_and_future_envelopes() {
+        let cache = ReplayCache::default();
+        let user = Uuid::new_v4();
+
+        assert!(cache.is_fresh_at(user, &envelope(0), SENT_AT));
+        assert!(cache.is_fresh_at(user, &envelope(0), SENT_AT + MAX_SKEW - 1));
+        assert!(cache.is_fresh_at(user, &envelope(0), SENT_AT - MAX_SKEW + 1));
+        assert!(!cache.is_fresh_at(user, &envelope(0), SENT_AT + MAX_SKEW));
+        assert!(!cache.is_fresh_at(user, &envelope(0), SENT_AT - MAX_SKEW));
+    }
+
+    #[test]
+    fn rejects_duplicates_per_user() {
+        let cache = ReplayCache::default();
+        let (user, other) = (Uuid::new_v4(), Uuid::new_v4());
+
+        assert!(cache.record_at(user, &envelope(1), SENT_AT));
+        assert!(!cache.is_fresh_at(user, &envelope(1), SENT_AT + 1));
+        assert!(!cache.record_at(user, &envelope(1), SENT_AT + 1));
+
+        assert!(cache.is_fresh_at(user, &envelope(2), SENT_AT + 1));
+        assert!(cache.is_fresh_at(other, &envelope(1), SENT_AT + 1));
+    }
+
+    #[test]
+    fn no_gap_at_the_window_edge() {
+        let cache = ReplayCache::default();
+        let user = Uuid::new_v4();
+        assert!(cache.record_at(user, &envelope(3), SENT_AT));
+
+        // the last moment it's remembered is the last moment it would be accepted
+        let last = SENT_AT + MAX_SKEW - 1;
+        cache.record_at(user, &envelope(4), last);
+        assert!(!cache.is_fresh_at(user, &envelope(3), last));
+
+        // pruned as it turns stale, and stale it stays
+        let edge = SENT_AT + MAX_SKEW;
+        cache.record_at(user, &envelope(5), edge);
+        assert!(!cache.seen.lock().unwrap().contains_key(&(user, [3; 12])));
+        assert!(!cache.is_fresh_at(user, &envelope(3), edge));
+    }
+}
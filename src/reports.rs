@@ -0,0 +1,221 @@
+use crate::{
+    boards::GLOBAL_BOARD, config::config, messages::FullMessage, reputation::{record, ReputationEvent}, user::User, util::clean
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+/// Categories a report can be filed under.
+pub const REPORT_REASONS: [&str; 5] = ["spam", "harassment", "hate", "sexual", "other"];
+
+const MAX_NOTE_LENGTH: usize = 500;
+
+#[derive(Deserialize)]
+pub struct NewReport {
+    pub message: Uuid,
+    pub reason: String,
+    #[serde(default)]
+    pub note: Option<String>
+}
+
+pub enum ReportOutcome {
+    // carries the message if this report was the one that got it hidden
+    Filed(Option<FullMessage>),
+    Duplicate,
+    RateLimited,
+    NotFound,
+    Invalid
+}
+
+/// Files a report from `user` against a published message they can see from `board`. Once open
+/// reports on a message come from enough distinct referral branches it is unpublished until a
+/// moderator looks.
+pub async fn file_report(
+    pool: &PgPool,
+    user: &User,
+    branch: Uuid,
+    board: Uuid,
+    report: &NewReport
+) -> sqlx::Result<ReportOutcome> {
+    if !REPORT_REASONS.contains(&report.reason.as_str()) {
+        return Ok(ReportOutcome::Invalid);
+    }
+
+    let note = report
+        .note
+        .as_deref()
+        .map(|note| clean(note.trim()))
+        .filter(|note| !note.is_empty())
+        .map(|note| note.chars().take(MAX_NOTE_LENGTH).collect::<String>());
+
+    let recent = sqlx::query_scalar!(
+        // language=postgresql
+        r#"SELECT count(*) AS "count!" FROM reports
+           WHERE reporter = $1 AND created_at > now() - interval '1 hour'"#,
+        user.id
+    )
+    .fetch_one(pool)
+    .await?;
+
+    if recent >= config().reports.per_hour {
+        return Ok(ReportOutcome::RateLimited);
+    }
+
+    // only what's actually on the reporter's board, and never their own
+    let filed = sqlx::query_scalar!(
+        // language=postgresql
+        "INSERT INTO reports (message, reporter, branch, reason, note)
+         SELECT id, $2, $3, $4, $5 FROM messages
+         WHERE id = $1 AND published AND author <> $2
+           AND (board_id = $6 OR (crossposted AND $6 = $7))
+         ON CONFLICT DO NOTHING
+         RETURNING id",
+        report.message,
+        user.id,
+        branch,
+        report.reason,
+        note,
+        board,
+        GLOBAL_BOARD
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    if filed.is_none() {
+        let already_reported = sqlx::query_scalar!(
+            // language=postgresql
+            r#"SELECT EXISTS (SELECT 1 FROM reports
+                              WHERE message = $1 AND reporter = $2) AS "exists!""#,
+            report.message,
+            user.id
+        )
+        .fetch_one(pool)
+        .await?;
+
+        return Ok(if already_reported {
+            ReportOutcome::Duplicate
+        } else {
+            ReportOutcome::NotFound
+        });
+    }
+
+    // banned reporters are still recorded but don't count, and admins are never hidden this way
+    let hidden = sqlx::query_as!(
+        FullMessage,
+        // language=postgresql
+        "UPDATE messages SET published = FALSE
+         WHERE id = $1 AND published
+           AND NOT (SELECT admin FROM users WHERE users.id = messages.author)
+           AND (SELECT count(DISTINCT reports.branch) FROM reports
+                JOIN users ON users.id = reports.reporter
+                WHERE reports.message = $1 AND reports.resolved_at IS NULL AND NOT users.banned) >= $2
+         RETURNING *",
+        report.message,
+        config().reports.hide_branches
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    if let Some(message) = &hidden {
+        record(pool, message.author, ReputationEvent::MessageAutoHidden).await?;
+    }
+
+    Ok(ReportOutcome::Filed(hidden))
+}
+
+/// One open report alongside what we know about whoever filed it.
+#[derive(Serialize, FromRow)]
+pub struct QueuedReport {
+    pub id: Uuid,
+    pub message: Uuid,
+    pub content: String,
+    pub published: bool,
+    pub reason: String,
+    pub note: Option<String>,
+    pub created_at: DateTime<Utc>,
+    // distinct branches with open reports on the same message
+    pub branches: i64,
+    pub reporter: Uuid,
+    pub reporter_branch: Uuid,
+    pub reporter_banned: bool,
+    pub reporter_joined: DateTime<Utc>,
+    pub reporter_filed: i64,
+    // how earlier reports from the same person were resolved
+    pub reporter_upheld: i64,
+    pub reporter_dismissed: i64
+}
+
+/// Open reports, the messages reported from the most branches first.
+pub async fn report_queue(pool: &PgPool) -> sqlx::Result<Vec<QueuedReport>> {
+    sqlx::query_as!(
+        QueuedReport,
+        // language=postgresql
+        r#"SELECT reports.id, reports.message, messages.content, messages.published,
+                  reports.reason, reports.note, reports.created_at,
+                  (SELECT count(DISTINCT others.branch) FROM reports others
+                   WHERE others.message = reports.message AND others.resolved_at IS NULL) AS "branches!",
+                  reports.reporter, reports.branch AS reporter_branch,
+                  users.banned AS reporter_banned, users.created_at AS reporter_joined,
+                  (SELECT count(*) FROM reports filed WHERE filed.reporter = reports.reporter) AS "reporter_filed!",
+                  (SELECT count(*) FROM reports filed
+                   WHERE filed.reporter = reports.reporter AND filed.upheld) AS "reporter_upheld!",
+                  (SELECT count(*) FROM reports filed
+                   WHERE filed.reporter = reports.reporter AND NOT filed.upheld) AS "reporter_dismissed!"
+           FROM reports
+           JOIN messages ON messages.id = reports.message
+           JOIN users ON users.id = reports.reporter
+           WHERE reports.resolved_at IS NULL
+           ORDER BY "branches!" DESC, reports.message, reports.created_at"#
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Closes every open report on `message`, settling the reputation of everyone involved. Upholding
+/// them also takes the message down if it's still up, which is returned so it can be pulled from boards.
+pub async fn resolve_reports(
+    pool: &PgPool,
+    message: Uuid,
+    upheld: bool
+) -> sqlx::Result<Option<FullMessage>> {
+    let reporters = sqlx::query_scalar!(
+        // language=postgresql
+        "UPDATE reports SET upheld = $2, resolved_at = now()
+         WHERE message = $1 AND resolved_at IS NULL
+         RETURNING reporter",
+        message,
+        upheld
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let verdict =
+        if upheld { ReputationEvent::ReportUpheld } else { ReputationEvent::ReportDismissed };
+    for reporter in &reporters {
+        record(pool, *reporter, verdict).await?;
+    }
+
+    if !upheld || reporters.is_empty() {
+        return Ok(None);
+    }
+
+    let author = sqlx::query_scalar!(
+        // language=postgresql
+        "SELECT author FROM messages WHERE id = $1",
+        message
+    )
+    .fetch_one(pool)
+    .await?;
+
+    record(pool, author, ReputationEvent::ReportedUpheld).await?;
+
+    sqlx::query_as!(
+        FullMessage,
+        // language=postgresql
+        "UPDATE messages SET published = FALSE WHERE id = $1 AND published RETURNING *",
+        message
+    )
+    .fetch_optional(pool)
+    .await
+}
@@ -0,0 +1,161 @@
+use crate::{config::config, user::User};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::Serialize;
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+/// Things that move a user's reputation. Admins don't have one, events about them are dropped.
+#[derive(Clone, Copy)]
+pub enum ReputationEvent {
+    // an admin published one of their held messages
+    MessageApproved,
+    // an admin unpublished one of their messages
+    MessageRejected,
+    // enough branches reported one of their messages to hide it
+    MessageAutoHidden,
+    // a moderator agreed with reports against one of their messages
+    ReportedUpheld,
+    // a moderator agreed or disagreed with a report they filed
+    ReportUpheld,
+    ReportDismissed,
+    // someone they invited was banned, or unbanned again
+    InviteeBanned,
+    InviteeUnbanned
+}
+
+impl ReputationEvent {
+    const fn name(self) -> &'static str {
+        match self {
+            Self::MessageApproved => "message_approved",
+            Self::MessageRejected => "message_rejected",
+            Self::MessageAutoHidden => "message_auto_hidden",
+            Self::ReportedUpheld => "reported_upheld",
+            Self::ReportUpheld => "report_upheld",
+            Self::ReportDismissed => "report_dismissed",
+            Self::InviteeBanned => "invitee_banned",
+            Self::InviteeUnbanned => "invitee_unbanned"
+        }
+    }
+
+    // a weight table, some weights just happen to match
+    #[allow(clippy::match_same_arms)]
+    const fn delta(self) -> f32 {
+        match self {
+            Self::MessageApproved => 2.0,
+            Self::MessageRejected => -3.0,
+            Self::MessageAutoHidden => -2.0,
+            Self::ReportedUpheld => -5.0,
+            Self::ReportUpheld => 1.0,
+            Self::ReportDismissed => -1.0,
+            Self::InviteeBanned => -5.0,
+            Self::InviteeUnbanned => 5.0
+        }
+    }
+}
+
+/// Records `event` against `user` and moves their score by its weight.
+pub async fn record(pool: &PgPool, user: Uuid, event: ReputationEvent) -> sqlx::Result<()> {
+    sqlx::query!(
+        // language=postgresql
+        "WITH target AS (
+             UPDATE users SET reputation = reputation + $3 WHERE id = $1 AND NOT admin RETURNING id
+         )
+         INSERT INTO reputation_events (user_id, event, delta)
+         SELECT id, $2, $3 FROM target",
+        user,
+        event.name(),
+        event.delta()
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Records `event` against whoever invited `user`, if anyone did.
+pub async fn record_for_referrer(
+    pool: &PgPool,
+    user: Uuid,
+    event: ReputationEvent
+) -> sqlx::Result<()> {
+    let referrer = sqlx::query_scalar!(
+        // language=postgresql
+        "SELECT user_referral FROM users WHERE id = $1",
+        user
+    )
+    .fetch_optional(pool)
+    .await?
+    .flatten();
+
+    match referrer {
+        Some(referrer) => record(pool, referrer, event).await,
+        None => Ok(())
+    }
+}
+
+#[derive(Serialize, FromRow)]
+pub struct ReputationEntry {
+    pub event: String,
+    pub delta: f32,
+    pub created_at: DateTime<Utc>
+}
+
+/// The most recent events behind a user's score, newest first.
+pub async fn history(pool: &PgPool, user: Uuid) -> sqlx::Result<Vec<ReputationEntry>> {
+    sqlx::query_as!(
+        ReputationEntry,
+        // language=postgresql
+        "SELECT event, delta, created_at FROM reputation_events
+         WHERE user_id = $1 ORDER BY created_at DESC LIMIT 100",
+        user
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Every event behind a user's score, oldest first, for exports that have to be complete.
+pub async fn all_events(pool: &PgPool, user: Uuid) -> sqlx::Result<Vec<ReputationEntry>> {
+    sqlx::query_as!(
+        ReputationEntry,
+        // language=postgresql
+        "SELECT event, delta, created_at FROM reputation_events
+         WHERE user_id = $1 ORDER BY created_at",
+        user
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// How much slack `censor` gives someone, scaling its limits.
+#[derive(Clone, Copy)]
+pub enum Standing {
+    // new accounts and anyone who has lost reputation
+    Probation,
+    Regular,
+    Trusted
+}
+
+impl Standing {
+    pub fn of(user: &User) -> Self {
+        let config = &config().reputation;
+        let new_account =
+            Utc::now() - user.created_at < TimeDelta::hours(config.new_account_hours);
+
+        if new_account || user.reputation <= config.probation {
+            Self::Probation
+        } else if user.reputation >= config.trusted {
+            Self::Trusted
+        } else {
+            Self::Regular
+        }
+    }
+
+    /// Multiplies score thresholds and message limits.
+    pub const fn leniency(self) -> f32 {
+        match self {
+            Self::Probation => 0.75,
+            Self::Regular => 1.0,
+            Self::Trusted => 1.25
+        }
+    }
+}
@@ -0,0 +1,242 @@
+use crate::config::{config, RetentionConfig, Secret};
+use anyhow::bail;
+use chrono::{DateTime, Utc};
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::{FromRow, PgPool};
+use std::{fmt::Write, net::IpAddr, str::FromStr, sync::Arc, time::Duration};
+use tokio::time::{interval, MissedTickBehavior};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+const RETENTION_INTERVAL: Duration = Duration::from_hours(1);
+const RETENTION_BATCH: i64 = 500;
+// short keys are as easy to brute force as the ips they hash
+const MIN_HASH_KEY_LENGTH: usize = 16;
+
+/// What happens to a user's ip and user agent once they're past retention.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RedactionMode {
+    // ips keep their /24 or /48, user agents just the product
+    Truncate,
+    // both become keyed hashes, still comparable but no longer readable
+    Hash
+}
+
+impl FromStr for RedactionMode {
+    type Err = ();
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "truncate" => Ok(Self::Truncate),
+            "hash" => Ok(Self::Hash),
+            _ => Err(())
+        }
+    }
+}
+
+/// `retention.hash_key`, once it's known to be long enough. There's no other way to make one, so
+/// nothing gets hashed without a real key: an unkeyed hash of an ip is as good as the ip.
+pub struct HashKey(Vec<u8>);
+
+impl HashKey {
+    pub fn new(key: &Secret) -> anyhow::Result<Self> {
+        let key = key.expose();
+        if key.len() < MIN_HASH_KEY_LENGTH {
+            bail!(
+                "retention.hash_key (or RETENTION_HASH_KEY) has to be at least \
+                 {MIN_HASH_KEY_LENGTH} characters, try `openssl rand -hex 32`"
+            );
+        }
+
+        Ok(Self(key.as_bytes().to_vec()))
+    }
+
+    /// Changing the key splits clusters into before and after.
+    pub fn hash(&self, value: &str) -> Vec<u8> {
+        // the extract step is exactly HMAC-SHA256, keyed by the salt
+        Hkdf::<Sha256>::extract(Some(&self.0), value.as_bytes()).0.to_vec()
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::with_capacity(bytes.len() * 2), |mut out, byte| {
+        let _ = write!(out, "{byte:02x}");
+        out
+    })
+}
+
+fn redact_ip(ip: &str, mode: RedactionMode, key: &HashKey) -> String {
+    match (mode, ip.parse::<IpAddr>()) {
+        (RedactionMode::Truncate, Ok(IpAddr::V4(ip))) => {
+            let [a, b, c, _] = ip.octets();
+            format!("{a}.{b}.{c}.0/24")
+        }
+        (RedactionMode::Truncate, Ok(IpAddr::V6(ip))) => {
+            let [a, b, c, ..] = ip.segments();
+            format!("{a:x}:{b:x}:{c:x}::/48")
+        }
+        // nothing sensible to cut an unparseable address down to
+        (RedactionMode::Truncate, Err(_)) | (RedactionMode::Hash, _) => hex(&key.hash(ip))
+    }
+}
+
+fn redact_user_agent(user_agent: &str, mode: RedactionMode, key: &HashKey) -> String {
+    match mode {
+        RedactionMode::Truncate => {
+            user_agent.split_once(" (").map_or(user_agent, |(product, _)| product).to_string()
+        }
+        RedactionMode::Hash => hex(&key.hash(user_agent))
+    }
+}
+
+pub async fn retention_task(pool: PgPool, key: Arc<HashKey>) {
+    let retention = &config().retention;
+    let mut retention_interval = interval(RETENTION_INTERVAL);
+    retention_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        retention_interval.tick().await;
+
+        match sweep(&pool, retention, &key).await {
+            Ok((0, 0)) => {}
+            Ok((hashed, redacted)) => {
+                info!("hashed network details of {hashed} users, redacted {redacted}");
+            }
+            Err(why) => warn!("failed to apply network retention: {why:?}")
+        }
+    }
+}
+
+/// Returns how many users had hashes backfilled and how many were redacted.
+async fn sweep(
+    pool: &PgPool,
+    retention: &RetentionConfig,
+    key: &HashKey
+) -> sqlx::Result<(usize, usize)> {
+    // anyone who signed up before hashes were taken, while the plain values are still around
+    let unhashed = sqlx::query!(
+        // language=postgresql
+        "SELECT id, ip, user_agent FROM users
+         WHERE ip_hash IS NULL AND redacted_at IS NULL LIMIT $1",
+        RETENTION_BATCH
+    )
+    .fetch_all(pool)
+    .await?;
+
+    for user in &unhashed {
+        sqlx::query!(
+            // language=postgresql
+            "UPDATE users SET ip_hash = $2, user_agent_hash = $3 WHERE id = $1",
+            user.id,
+            key.hash(&user.ip),
+            user.user_agent.as_deref().map(|user_agent| key.hash(user_agent))
+        )
+        .execute(pool)
+        .await?;
+    }
+
+    // 0 keeps everything forever, hashes are still taken for clustering
+    if retention.network_days == 0 {
+        return Ok((unhashed.len(), 0));
+    }
+
+    let due = sqlx::query!(
+        // language=postgresql
+        "SELECT id, ip, user_agent FROM users
+         WHERE redacted_at IS NULL AND ip_hash IS NOT NULL
+           AND created_at < now() - make_interval(days => $1)
+         LIMIT $2",
+        retention.network_days,
+        RETENTION_BATCH
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mode = retention.network_mode;
+    for user in &due {
+        sqlx::query!(
+            // language=postgresql
+            "UPDATE users SET ip = $2, user_agent = $3, redacted_at = now() WHERE id = $1",
+            user.id,
+            redact_ip(&user.ip, mode, key),
+            user.user_agent.as_deref().map(|user_agent| redact_user_agent(user_agent, mode, key))
+        )
+        .execute(pool)
+        .await?;
+    }
+
+    Ok((unhashed.len(), due.len()))
+}
+
+/// Someone who signed up from the same address as the user being looked at.
+#[derive(Serialize, FromRow)]
+pub struct RelatedUser {
+    pub id: Uuid,
+    pub code: String,
+    pub banned: bool,
+    pub created_at: DateTime<Utc>,
+    pub same_user_agent: bool
+}
+
+/// Possible sock puppets, going by hashes so it keeps working past retention.
+pub async fn related_users(pool: &PgPool, user: Uuid) -> sqlx::Result<Vec<RelatedUser>> {
+    sqlx::query_as!(
+        RelatedUser,
+        // language=postgresql
+        r#"SELECT others.id, others.code, others.banned, others.created_at,
+                  COALESCE(others.user_agent_hash = target.user_agent_hash, FALSE) AS "same_user_agent!"
+           FROM users target
+           JOIN users others ON others.ip_hash = target.ip_hash AND others.id <> target.id
+           WHERE target.id = $1
+           ORDER BY others.created_at DESC LIMIT 100"#,
+        user
+    )
+    .fetch_all(pool)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(key: &str) -> HashKey {
+        HashKey::new(&key.parse().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn truncates_ips_to_their_network() {
+        let key = key("0123456789abcdef");
+        assert_eq!(redact_ip("203.0.113.77", RedactionMode::Truncate, &key), "203.0.113.0/24");
+        assert_eq!(
+            redact_ip("2001:db8:abcd:12::1", RedactionMode::Truncate, &key),
+            "2001:db8:abcd::/48"
+        );
+    }
+
+    #[test]
+    fn truncates_user_agents_to_the_product() {
+        let key = key("0123456789abcdef");
+        let user_agent = "Mozilla/5.0 (X11; Linux x86_64) Gecko/20100101 Firefox/130.0";
+        assert_eq!(redact_user_agent(user_agent, RedactionMode::Truncate, &key), "Mozilla/5.0");
+        assert_eq!(redact_user_agent("curl/8.9.1", RedactionMode::Truncate, &key), "curl/8.9.1");
+    }
+
+    #[test]
+    fn hashes_depend_on_the_key() {
+        let hash = key("0123456789abcdef").hash("203.0.113.77");
+        assert_eq!(hash.len(), 32);
+        assert_eq!(hash, key("0123456789abcdef").hash("203.0.113.77"));
+        assert_ne!(hash, key("fedcba9876543210").hash("203.0.113.77"));
+        assert_ne!(hash, key("0123456789abcdef").hash("203.0.113.78"));
+        assert_eq!(hex(&[0x00, 0x0f, 0xa5]), "000fa5");
+    }
+
+    #[test]
+    fn refuses_short_keys() {
+        assert!(HashKey::new(&Secret::default()).is_err());
+        assert!(HashKey::new(&"0123456789abcde".parse().unwrap()).is_err());
+    }
+}
@@ -0,0 +1,165 @@
+use crate::{
+    boards::GLOBAL_BOARD, censor::score_content, messages::FullMessage, util::clean, ws::WebsocketActorMessage
+};
+use chrono::{DateTime, Utc};
+use rustrict::Censor;
+use serde::Serialize;
+use sqlx::{FromRow, PgPool};
+use std::time::Duration;
+use tokio::{
+    sync::mpsc::Sender, time::{interval, MissedTickBehavior}
+};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+// how late a scheduled message can be at worst
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(5);
+const SCHEDULE_BATCH: i64 = 50;
+
+/// A message an admin has queued up to post later.
+#[derive(Serialize, FromRow)]
+pub struct ScheduledMessage {
+    pub id: Uuid,
+    pub content: String,
+    pub author: Uuid,
+    pub location: Option<String>,
+    pub publish_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub published_at: Option<DateTime<Utc>>,
+    pub message_id: Option<Uuid>
+}
+
+/// Everything still waiting to go out, soonest first.
+pub async fn pending_messages(pool: &PgPool) -> sqlx::Result<Vec<ScheduledMessage>> {
+    sqlx::query_as!(
+        ScheduledMessage,
+        // language=postgresql
+        "SELECT * FROM scheduled_messages WHERE published_at IS NULL ORDER BY publish_at"
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Returns nothing if the content cleans down to nothing or the location doesn't exist.
+pub async fn schedule_message(
+    pool: &PgPool,
+    author: Uuid,
+    content: &str,
+    location: Option<&str>,
+    publish_at: DateTime<Utc>
+) -> sqlx::Result<Option<ScheduledMessage>> {
+    let content = clean(content.trim());
+    if content.is_empty() {
+        return Ok(None);
+    }
+
+    sqlx::query_as!(
+        ScheduledMessage,
+        // language=postgresql
+        "INSERT INTO scheduled_messages (content, author, location, publish_at)
+         SELECT $1, $2, $3, $4
+         WHERE $3::text IS NULL OR EXISTS (SELECT 1 FROM locations WHERE code = $3)
+         RETURNING *",
+        content,
+        author,
+        location.map(str::to_lowercase),
+        publish_at
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// Only messages that haven't gone out yet can be cancelled. Returns false otherwise.
+pub async fn cancel_message(pool: &PgPool, id: Uuid) -> sqlx::Result<bool> {
+    sqlx::query!(
+        // language=postgresql
+        "DELETE FROM scheduled_messages WHERE id = $1 AND published_at IS NULL",
+        id
+    )
+    .execute(pool)
+    .await
+    .map(|result| result.rows_affected() > 0)
+}
+
+/// Posts scheduled messages as they come due. Everything lives in the database, so anything
+/// that came due while we were down goes out on the first tick after a restart.
+pub async fn scheduler_task(pool: PgPool, tx: Sender<WebsocketActorMessage>) {
+    let mut schedule_interval = interval(SCHEDULE_INTERVAL);
+    schedule_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        schedule_interval.tick().await;
+
+        match publish_due(&pool).await {
+            Ok(messages) => {
+                if messages.is_empty() {
+                    continue;
+                }
+
+                info!("published {} scheduled messages", messages.len());
+
+                for message in messages {
+                    let update = WebsocketActorMessage::Message { message, is_update: false };
+                    if tx.send(update).await.is_err() {
+                        return;
+                    }
+                }
+            }
+            Err(why) => warn!("failed to publish scheduled messages: {why:?}")
+        }
+    }
+}
+
+async fn publish_due(pool: &PgPool) -> sqlx::Result<Vec<FullMessage>> {
+    let mut transaction = pool.begin().await?;
+
+    // locked so a second instance can't post the same ones
+    let due = sqlx::query!(
+        // language=postgresql
+        r#"SELECT scheduled_messages.id, content, author,
+                  COALESCE(locations.board_id, $2) AS "board!"
+           FROM scheduled_messages LEFT JOIN locations ON locations.code = scheduled_messages.location
+           WHERE published_at IS NULL AND publish_at <= now()
+           ORDER BY publish_at LIMIT $1
+           FOR UPDATE OF scheduled_messages SKIP LOCKED"#,
+        SCHEDULE_BATCH,
+        GLOBAL_BOARD
+    )
+    .fetch_all(&mut *transaction)
+    .await?;
+
+    let mut published = Vec::with_capacity(due.len());
+
+    for scheduled in due {
+        // admins skip the censor, the score is only kept for search and moderation
+        let score = score_content(Censor::from_str(&scheduled.content).analyze());
+
+        let message = sqlx::query_as!(
+            FullMessage,
+            // language=postgresql
+            "INSERT INTO messages (content, author, published, score, board_id)
+             VALUES ($1, $2, TRUE, $3, $4) RETURNING *",
+            scheduled.content,
+            scheduled.author,
+            score,
+            scheduled.board
+        )
+        .fetch_one(&mut *transaction)
+        .await?;
+
+        sqlx::query!(
+            // language=postgresql
+            "UPDATE scheduled_messages SET published_at = now(), message_id = $2 WHERE id = $1",
+            scheduled.id,
+            message.id
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        published.push(message);
+    }
+
+    transaction.commit().await?;
+
+    Ok(published)
+}
@@ -0,0 +1,95 @@
+use crate::{boards::GLOBAL_BOARD, messages::FullMessage};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+const MAX_RESULTS: i64 = 100;
+
+/// Admin search filters, anything left out doesn't filter.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct SearchQuery {
+    // websearch syntax: quoted phrases, `or`, `-excluded`
+    pub q: String,
+    pub author: Option<Uuid>,
+    // messages from anyone whose referral chain starts at this location
+    pub location: Option<String>,
+    // what shows on this board, crossposts to the global board included
+    pub board: Option<Uuid>,
+    pub published: Option<bool>,
+    pub min_score: Option<f32>,
+    pub max_score: Option<f32>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>
+}
+
+#[derive(Serialize)]
+pub struct SearchResult {
+    #[serde(flatten)]
+    pub message: FullMessage,
+    // the content with matches wrapped in <mark>
+    pub highlight: String
+}
+
+/// Newest matches first.
+pub async fn search_messages(
+    pool: &PgPool,
+    query: &SearchQuery
+) -> sqlx::Result<Vec<SearchResult>> {
+    let rows = sqlx::query!(
+        // language=postgresql
+        r#"WITH RECURSIVE located AS (
+               SELECT id FROM users WHERE location_referral = $3
+               UNION ALL
+               SELECT users.id FROM users JOIN located ON users.user_referral = located.id
+           )
+           SELECT messages.*,
+                  ts_headline('english', content, websearch_to_tsquery('english', $1),
+                              'StartSel=<mark>, StopSel=</mark>, HighlightAll=true') AS "highlight!"
+           FROM messages
+           WHERE ($1 = '' OR to_tsvector('english', content) @@ websearch_to_tsquery('english', $1))
+             AND ($2::uuid IS NULL OR author = $2)
+             AND ($3::text IS NULL OR author IN (SELECT id FROM located))
+             AND ($4::bool IS NULL OR published = $4)
+             AND ($5::float4 IS NULL OR score >= $5)
+             AND ($6::float4 IS NULL OR score <= $6)
+             AND ($7::timestamptz IS NULL OR created_at >= $7)
+             AND ($8::timestamptz IS NULL OR created_at < $8)
+             AND ($10::uuid IS NULL OR board_id = $10 OR (crossposted AND $10 = $11))
+           ORDER BY created_at DESC, id DESC
+           LIMIT $9"#,
+        query.q.trim(),
+        query.author,
+        query.location.as_deref().map(str::to_lowercase),
+        query.published,
+        query.min_score,
+        query.max_score,
+        query.from,
+        query.to,
+        MAX_RESULTS,
+        query.board,
+        GLOBAL_BOARD
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| SearchResult {
+            message: FullMessage {
+                id: row.id,
+                content: row.content,
+                author: row.author,
+                published: row.published,
+                score: row.score,
+                created_at: row.created_at,
+                parent_id: row.parent_id,
+                expires_at: row.expires_at,
+                board_id: row.board_id,
+                crossposted: row.crossposted
+            },
+            highlight: row.highlight
+        })
+        .collect())
+}
@@ -0,0 +1,238 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload}, Aes256Gcm, Nonce
+};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+const KEY_INFO: &[u8] = b"message-board session";
+// header submissions pick their own random nonces, so they get a key of their own
+const ENVELOPE_KEY_INFO: &[u8] = b"message-board envelope";
+
+// each direction gets its own nonce prefix so the shared key never sees the same nonce twice
+const CLIENT_NONCE_PREFIX: [u8; 4] = [0, 0, 0, 0];
+const SERVER_NONCE_PREFIX: [u8; 4] = [0, 0, 0, 1];
+
+/// Key exchange state for a single connection, started by the server as soon as the client connects.
+pub enum SessionState {
+    Pending { secret: EphemeralSecret, public: PublicKey },
+    Established(Box<Session>),
+    Failed
+}
+
+impl SessionState {
+    pub fn new() -> Self {
+        let secret = EphemeralSecret::random();
+        let public = PublicKey::from(&secret);
+
+        Self::Pending { secret, public }
+    }
+
+    pub fn public_key(&self) -> Option<[u8; 32]> {
+        match self {
+            Self::Pending { public, .. } => Some(public.to_bytes()),
+            _ => None
+        }
+    }
+
+    /// Completes the exchange with the client's public key. Only the first attempt counts.
+    pub fn establish(&mut self, client_public: [u8; 32]) -> bool {
+        // a repeated handshake leaves the session it would have replaced alone
+        if !matches!(self, Self::Pending { .. }) {
+            return false;
+        }
+
+        let Self::Pending { secret, public } = std::mem::replace(self, Self::Failed) else {
+            return false;
+        };
+
+        let client_public = PublicKey::from(client_public);
+        let shared = secret.diffie_hellman(&client_public);
+
+        // low order points would leave the key up to whoever picked them
+        if !shared.was_contributory() {
+            return false;
+        }
+
+        let salt = [public.as_bytes().as_slice(), client_public.as_bytes().as_slice()].concat();
+        let hkdf = Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes());
+        let (mut key, mut envelope_key) = ([0u8; 32], [0u8; 32]);
+        if hkdf.expand(KEY_INFO, &mut key).is_err()
+            || hkdf.expand(ENVELOPE_KEY_INFO, &mut envelope_key).is_err()
+        {
+            return false;
+        }
+
+        *self = Self::Established(Box::new(Session {
+            cipher: Aes256Gcm::new(&key.into()),
+            envelope: Aes256Gcm::new(&envelope_key.into()),
+            sent: 0,
+            received: 0
+        }));
+
+        true
+    }
+
+    pub const fn established(&mut self) -> Option<&mut Session> {
+        match self {
+            Self::Established(session) => Some(session),
+            _ => None
+        }
+    }
+}
+
+pub struct Session {
+    cipher: Aes256Gcm,
+    envelope: Aes256Gcm,
+    sent: u64,
+    received: u64
+}
+
+impl Session {
+    /// What header submissions made during this session are sealed with.
+    pub fn envelope_cipher(&self) -> Aes256Gcm {
+        self.envelope.clone()
+    }
+
+    /// Encrypts `plaintext` as `[counter u64][ciphertext + tag]`, binding the frame kind in as associated data.
+    pub fn seal(&mut self, kind: u8, plaintext: &[u8]) -> Option<Vec<u8>> {
+        self.sent += 1;
+
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce(SERVER_NONCE_PREFIX, self.sent), Payload { msg: plaintext, aad: &[kind] })
+            .ok()?;
+
+        let mut sealed = self.sent.to_be_bytes().to_vec();
+        sealed.extend_from_slice(&ciphertext);
+
+        Some(sealed)
+    }
+
+    /// Opens a client frame, rejecting anything tampered with or not newer than the last one accepted.
+    pub fn open(&mut self, kind: u8, sealed: &[u8]) -> Option<Vec<u8>> {
+        let counter = u64::from_be_bytes(sealed.get(..8)?.try_into().ok()?);
+        if counter <= self.received {
+            return None;
+        }
+
+        let plaintext = self
+            .cipher
+            .decrypt(&nonce(CLIENT_NONCE_PREFIX, counter), Payload { msg: &sealed[8..], aad: &[kind] })
+            .ok()?;
+
+        self.received = counter;
+        Some(plaintext)
+    }
+}
+
+fn nonce(prefix: [u8; 4], counter: u64) -> Nonce<aes_gcm::aead::consts::U12> {
+    let mut nonce = [0u8; 12];
+    nonce[..4].copy_from_slice(&prefix);
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+
+    nonce.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the browser's half of the exchange
+    struct Client {
+        cipher: Aes256Gcm,
+        sent: u64
+    }
+
+    fn connect() -> (SessionState, Client) {
+        let mut server = SessionState::new();
+        let server_public = server.public_key().unwrap();
+
+        let secret = EphemeralSecret::random();
+        let public = PublicKey::from(&secret);
+        let shared = secret.diffie_hellman(&PublicKey::from(server_public));
+
+        let salt = [server_public.as_slice(), public.as_bytes().as_slice()].concat();
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes()).expand(KEY_INFO, &mut key).unwrap();
+
+        assert!(server.establish(public.to_bytes()));
+        (server, Client { cipher: Aes256Gcm::new(&key.into()), sent: 0 })
+    }
+
+    impl Client {
+        fn seal(&mut self, kind: u8, plaintext: &[u8]) -> Vec<u8> {
+            self.sent += 1;
+            self.seal_as(self.sent, kind, plaintext)
+        }
+
+        fn seal_as(&self, counter: u64, kind: u8, plaintext: &[u8]) -> Vec<u8> {
+            let nonce = nonce(CLIENT_NONCE_PREFIX, counter);
+            let ciphertext =
+                self.cipher.encrypt(&nonce, Payload { msg: plaintext, aad: &[kind] }).unwrap();
+            [counter.to_be_bytes().as_slice(), &ciphertext].concat()
+        }
+    }
+
+    #[test]
+    fn round_trips_both_ways() {
+        let (mut server, mut client) = connect();
+        let session = server.established().unwrap();
+
+        assert_eq!(session.open(1, &client.seal(1, b"hello")).as_deref(), Some(&b"hello"[..]));
+
+        let sealed = session.seal(0, b"world").unwrap();
+        let (counter, ciphertext) = sealed.split_at(8);
+        let nonce = nonce(SERVER_NONCE_PREFIX, u64::from_be_bytes(counter.try_into().unwrap()));
+        let opened = client.cipher.decrypt(&nonce, Payload { msg: ciphertext, aad: &[0] });
+        assert_eq!(opened.unwrap(), b"world");
+    }
+
+    #[test]
+    fn rejects_replays_and_old_counters() {
+        let (mut server, mut client) = connect();
+        let session = server.established().unwrap();
+
+        let first = client.seal(1, b"first");
+        let second = client.seal(1, b"second");
+
+        assert!(session.open(1, &second).is_some());
+        // arrived late, or sent again, both look the same
+        assert!(session.open(1, &first).is_none());
+        assert!(session.open(1, &second).is_none());
+        assert!(session.open(1, &client.seal(1, b"third")).is_some());
+    }
+
+    #[test]
+    fn rejects_tampering() {
+        let (mut server, mut client) = connect();
+        let session = server.established().unwrap();
+
+        // bound to the frame kind it was sealed as
+        assert!(session.open(3, &client.seal(1, b"submit")).is_none());
+
+        let mut sealed = client.seal(1, b"submit");
+        *sealed.last_mut().unwrap() ^= 1;
+        assert!(session.open(1, &sealed).is_none());
+
+        // a counter that was never used doesn't poison the ones after it
+        assert!(session.open(1, &sealed[..4]).is_none());
+        assert!(session.open(1, &client.seal_as(u64::MAX, 1, b"x")[..20]).is_none());
+        assert!(session.open(1, &client.seal(1, b"still fine")).is_some());
+    }
+
+    #[test]
+    fn only_establishes_once() {
+        let (mut server, _) = connect();
+        assert!(server.public_key().is_none());
+        assert!(!server.establish(PublicKey::from(&EphemeralSecret::random()).to_bytes()));
+        assert!(server.established().is_some());
+    }
+
+    #[test]
+    fn refuses_low_order_points() {
+        let mut server = SessionState::new();
+        assert!(!server.establish([0; 32]));
+        assert!(server.established().is_none());
+    }
+}
@@ -0,0 +1,141 @@
+use std::{
+    collections::HashMap, time::{Duration, Instant}
+};
+use uuid::Uuid;
+
+// clients may only refresh their typing state this often
+const RATE_LIMIT: Duration = Duration::from_secs(2);
+// typing state is dropped if it isn't refreshed in time
+const EXPIRY: Duration = Duration::from_secs(5);
+
+struct Typist {
+    board: Uuid,
+    at: Instant
+}
+
+/// Who is typing where. Every change reports the boards whose counts moved, so counts only go
+/// out when they're different and only to the boards they're about.
+#[derive(Default)]
+pub struct Typing {
+    typists: HashMap<Uuid, Typist>
+}
+
+impl Typing {
+    /// Marks `id` as typing on `board`, returning the boards whose counts changed, none if they
+    /// already were. Refreshes faster than the rate limit are ignored.
+    pub fn mark(&mut self, id: Uuid, board: Uuid) -> Vec<Uuid> {
+        if let Some(typist) = self.typists.get_mut(&id).filter(|typist| typist.board == board) {
+            if typist.at.elapsed() >= RATE_LIMIT {
+                typist.at = Instant::now();
+            }
+            return Vec::new();
+        }
+
+        // someone moved to another board while typing stops counting on the old one
+        let previous = self.typists.insert(id, Typist { board, at: Instant::now() });
+        previous.map(|previous| previous.board).into_iter().chain([board]).collect()
+    }
+
+    /// Returns the board `id` was typing on, if they were.
+    pub fn stop(&mut self, id: &Uuid) -> Option<Uuid> {
+        self.typists.remove(id).map(|typist| typist.board)
+    }
+
+    /// Drops anyone who hasn't refreshed in time, returning the boards they were on.
+    pub fn expire(&mut self) -> Vec<Uuid> {
+        let mut boards = Vec::new();
+
+        self.typists.retain(|_, typist| {
+            let alive = typist.at.elapsed() < EXPIRY;
+            if !alive && !boards.contains(&typist.board) {
+                boards.push(typist.board);
+            }
+            alive
+        });
+
+        boards
+    }
+
+    /// How many people besides `id` are typing on `board`, or anywhere without one, so nobody
+    /// gets counted on their own screen.
+    pub fn count_excluding(&self, id: &Uuid, board: Option<Uuid>) -> usize {
+        self.typists
+            .iter()
+            .filter(|&(typist, Typist { board: on, .. })| {
+                typist != id && board.is_none_or(|board| board == *on)
+            })
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOARD: Uuid = Uuid::from_u128(1);
+    const OTHER_BOARD: Uuid = Uuid::from_u128(2);
+
+    #[test]
+    fn only_starting_counts_as_a_change() {
+        let mut typing = Typing::default();
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+
+        assert_eq!(typing.mark(a, BOARD), vec![BOARD]);
+        assert!(typing.mark(a, BOARD).is_empty());
+        assert_eq!(typing.mark(b, OTHER_BOARD), vec![OTHER_BOARD]);
+        // moving boards is a change for both
+        assert_eq!(typing.mark(a, OTHER_BOARD), vec![BOARD, OTHER_BOARD]);
+
+        assert_eq!(typing.stop(&a), Some(OTHER_BOARD));
+        assert_eq!(typing.stop(&a), None);
+        assert_eq!(typing.mark(a, BOARD), vec![BOARD]);
+    }
+
+    #[test]
+    fn counts_are_per_board() {
+        let mut typing = Typing::default();
+        let (a, b, c) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+
+        typing.mark(a, BOARD);
+        typing.mark(b, BOARD);
+        typing.mark(c, OTHER_BOARD);
+
+        assert_eq!(typing.count_excluding(&a, Some(BOARD)), 1);
+        assert_eq!(typing.count_excluding(&c, Some(BOARD)), 2);
+        assert_eq!(typing.count_excluding(&a, Some(OTHER_BOARD)), 1);
+        assert_eq!(typing.count_excluding(&c, Some(OTHER_BOARD)), 0);
+        assert_eq!(typing.count_excluding(&a, None), 2);
+    }
+
+    #[test]
+    fn expiry_reports_each_board_once() {
+        let mut typing = Typing::default();
+        let stale = Instant::now().checked_sub(EXPIRY).unwrap();
+
+        for _ in 0..3 {
+            typing.typists.insert(Uuid::new_v4(), Typist { board: BOARD, at: stale });
+        }
+        typing.mark(Uuid::new_v4(), OTHER_BOARD);
+
+        assert_eq!(typing.expire(), vec![BOARD]);
+        assert!(typing.expire().is_empty());
+        assert_eq!(typing.count_excluding(&Uuid::nil(), None), 1);
+    }
+
+    #[test]
+    fn refreshes_are_rate_limited() {
+        let mut typing = Typing::default();
+        let id = Uuid::new_v4();
+
+        typing.mark(id, BOARD);
+        let first = typing.typists[&id].at;
+        typing.mark(id, BOARD);
+        assert_eq!(typing.typists[&id].at, first);
+
+        // past the rate limit, so this one counts and keeps them around
+        let stale = Instant::now().checked_sub(RATE_LIMIT).unwrap();
+        typing.typists.get_mut(&id).unwrap().at = stale;
+        typing.mark(id, BOARD);
+        assert!(typing.typists[&id].at > stale);
+    }
+}
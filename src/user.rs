@@ -1,4 +1,4 @@
-use crate::{util::WE, AppState};
+use crate::{boards::GLOBAL_BOARD, util::WE, AppState};
 use anyhow::anyhow;
 use axum::{
     extract::FromRequestParts, http::{
@@ -8,7 +8,7 @@ use axum::{
 use base64::{prelude::BASE64_STANDARD, Engine};
 use chrono::{DateTime, Utc};
 use serde::Serialize;
-use sqlx::FromRow;
+use sqlx::{FromRow, PgPool};
 use std::convert::Infallible;
 use uuid::Uuid;
 
@@ -27,7 +27,20 @@ pub struct User {
     pub user_agent: Option<String>,
 
     pub banned: bool,
-    pub created_at: DateTime<Utc>
+    pub created_at: DateTime<Utc>,
+    // moved by moderation events, see `reputation`
+    pub reputation: f32,
+    // invite quota override and branch freeze, see `invites`
+    pub invite_limit: Option<i32>,
+    pub invites_frozen: bool,
+    // see `retention`, the hashes only mean anything compared to each other in the database
+    #[allow(dead_code)]
+    #[serde(skip)]
+    pub ip_hash: Option<Vec<u8>>,
+    #[allow(dead_code, clippy::struct_field_names)]
+    #[serde(skip)]
+    pub user_agent_hash: Option<Vec<u8>>,
+    pub redacted_at: Option<DateTime<Utc>>
 }
 
 impl User {
@@ -38,12 +51,43 @@ impl User {
     pub fn encoded_id(&self) -> String {
         base64::engine::general_purpose::STANDARD.encode(self.id.to_string())
     }
+}
 
-    pub fn encryption_key(&self) -> Vec<u8> {
-        let uuid_string = self.id.to_string();
-        let uuid_first_bytes = &uuid_string.as_bytes()[0..16];
+/// Where a user ultimately came from: the root of their referral chain and the location it joined through.
+#[derive(FromRow, Clone, Serialize)]
+pub struct Lineage {
+    pub branch: Uuid,
+    pub location: Option<String>,
+    // the board the root's location maps to
+    pub board: Uuid
+}
 
-        uuid_first_bytes.to_vec()
+impl User {
+    pub async fn lineage(&self, pool: &PgPool) -> sqlx::Result<Lineage> {
+        let root = sqlx::query_as!(
+            Lineage,
+            // language=postgresql
+            r#"WITH RECURSIVE chain AS (
+                SELECT id, user_referral, location_referral FROM users WHERE id = $1
+                UNION ALL
+                SELECT u.id, u.user_referral, u.location_referral FROM users u
+                JOIN chain c ON u.id = c.user_referral
+            )
+            SELECT chain.id AS "branch!", chain.location_referral AS location,
+                   COALESCE(locations.board_id, $2) AS "board!"
+            FROM chain LEFT JOIN locations ON locations.code = chain.location_referral
+            WHERE chain.user_referral IS NULL LIMIT 1"#,
+            self.id,
+            GLOBAL_BOARD
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(root.unwrap_or_else(|| Lineage {
+            branch: self.id,
+            location: self.location_referral.clone(),
+            board: GLOBAL_BOARD
+        }))
     }
 }
 
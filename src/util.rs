@@ -1,4 +1,4 @@
-use crate::{fallback, AppState};
+use crate::{fallback, ws::MAX_SUBMISSION_BYTES, AppState};
 use anyhow::Context;
 use askama::Template;
 use axum::{
@@ -6,9 +6,9 @@ use axum::{
 };
 use base64::Engine;
 use minify_html::Cfg;
-use rand::prelude::IndexedRandom;
-use std::{cell::LazyCell, convert::Infallible, net::IpAddr};
+use std::convert::Infallible;
 use tracing::warn;
+use uuid::Uuid;
 
 #[derive(Debug)]
 pub struct WE(pub anyhow::Error);
@@ -39,32 +39,6 @@ pub fn clean(content: &str) -> String {
     ammonia::Builder::empty().clean(content).to_string()
 }
 
-pub struct ClientIp(pub IpAddr);
-
-impl FromRequestParts<AppState> for ClientIp {
-    type Rejection = Infallible;
-
-    async fn from_request_parts(
-        parts: &mut Parts,
-        _state: &AppState
-    ) -> Result<Self, Self::Rejection> {
-        Ok(Self(
-            parts
-                .headers
-                .get("CF-Connecting-IP")
-                .and_then(|ip| ip.to_str().ok())
-                .and_then(|ip| ip.parse::<IpAddr>().ok())
-                .unwrap_or_else(|| {
-                    #[cfg(not(debug_assertions))]
-                    panic!("failed to get client ip");
-
-                    #[cfg(debug_assertions)]
-                    IpAddr::V4(std::net::Ipv4Addr::new(0, 0, 0, 0))
-                })
-        ))
-    }
-}
-
 pub struct MinifiedHtml<H: Template>(pub H);
 
 const MINIFY_CFG: Cfg = Cfg {
@@ -103,59 +77,52 @@ impl FromRequestParts<AppState> for MaybeUserAgent {
     }
 }
 
-pub struct MessageAndIvFromHeaders(pub Vec<u8>, pub Vec<u8>);
+/// A header submission: `[sent at i64 ms][nonce 12][connection id 16]` smuggled in the fake user
+/// agent, and the AES-GCM ciphertext + tag in the cache header. It's sealed with the envelope key
+/// of that connection's session, and the timestamp is bound in as associated data.
+pub struct EnvelopeFromHeaders {
+    pub sent_at: i64,
+    pub nonce: [u8; 12],
+    pub connection: Uuid,
+    pub ciphertext: Vec<u8>
+}
 
-impl FromRequestParts<AppState> for MessageAndIvFromHeaders {
+impl FromRequestParts<AppState> for EnvelopeFromHeaders {
     type Rejection = WE;
 
     async fn from_request_parts(parts: &mut Parts, _: &AppState) -> Result<Self, Self::Rejection> {
         let raw_content_header =
             parts.headers.get("CF-Cache-Identifier").context("failed to get header")?;
 
-        let raw_iv_header = parts
+        let raw_envelope_header = parts
             .headers
             .get("Uses-Agent")
             .and_then(|ua| ua.to_str().ok())
             .and_then(|ua| ua.split_once("Mozilla/5.0 (Windows NT 10.0; Win64; x64; "))
-            .and_then(|(_, iv)| iv.split(')').next())
-            .context("failed to get iv")?;
+            .and_then(|(_, envelope)| envelope.split(')').next())
+            .context("failed to get envelope")?;
 
-        let content_bytes = base64::engine::general_purpose::STANDARD.decode(raw_content_header)?;
-        if content_bytes.len() > 1024 {
+        let ciphertext = base64::engine::general_purpose::STANDARD.decode(raw_content_header)?;
+        if ciphertext.len() > MAX_SUBMISSION_BYTES {
             return Err(WE(anyhow::anyhow!("content too long")));
         }
 
-        let iv_bytes = base64::engine::general_purpose::STANDARD.decode(raw_iv_header)?;
-        if iv_bytes.len() != 16 {
-            return Err(WE(anyhow::anyhow!("iv length is not 16")));
-        }
-
-        Ok(Self(content_bytes, iv_bytes))
+        let envelope_bytes = base64::engine::general_purpose::STANDARD.decode(raw_envelope_header)?;
+        let (Some(sent_at), Some(nonce), Some(connection)) =
+            (envelope_bytes.get(..8), envelope_bytes.get(8..20), envelope_bytes.get(20..))
+        else {
+            return Err(WE(anyhow::anyhow!("envelope is too short")));
+        };
+
+        Ok(Self {
+            sent_at: i64::from_be_bytes(sent_at.try_into()?),
+            nonce: nonce.try_into()?,
+            connection: Uuid::from_slice(connection)?,
+            ciphertext
+        })
     }
 }
 
-const WORDS_STRING_LIST: &str = include_str!("../assets/all_english_words_clean.txt");
-#[allow(clippy::declare_interior_mutable_const)]
-const WORDS_ARRAY: LazyCell<Vec<&str>> = LazyCell::new(|| WORDS_STRING_LIST.lines().collect());
-
-const SEPARATORS: &[char] = &['-', '_', '.'];
-
-pub fn generate_code() -> String {
-    let mut rng = rand::rng();
-
-    #[allow(clippy::borrow_interior_mutable_const)]
-    let words =
-        WORDS_ARRAY.choose_multiple(&mut rng, 2).map(ToString::to_string).collect::<Vec<String>>();
-
-    let [first_word, second_word] = &words[..] else {
-        unreachable!();
-    };
-
-    let separator = SEPARATORS.choose(&mut rng).unwrap();
-
-    format!("{first_word}{separator}{second_word}")
-}
-
 pub struct OptionalExtractor<T>(pub Option<T>);
 
 impl<T> FromRequestParts<AppState> for OptionalExtractor<T>
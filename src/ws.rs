@@ -1,36 +1,236 @@
-use crate::{messages::FullMessage, user::User, util::FallibleExtractor, AppState};
+use crate::{
+    announcements::Announcement, boards::GLOBAL_BOARD, controller::{post_message, NewMessage, PostOutcome}, expiry::TTL_CHOICES, frames::{
+        Ack, DeleteMessage, Handshake, MessageEncoder, PresenceCount, ReactionTally, Reply, TypingCount
+    }, history::History, messages::{FullMessage, StandardMessage}, presence::{Presence, PresenceReport, PresenceSnapshot}, reactions::{toggle_reaction, ReactionCount}, session::SessionState, typing::Typing, user::{Lineage, User}, util::FallibleExtractor, AppState
+};
+use aes_gcm::Aes256Gcm;
 use axum::{
-    extract::{
+    body::Bytes, extract::{
         ws::{Message, Utf8Bytes, WebSocket}, State, WebSocketUpgrade
-    }, response::Response
-};
-use cbc::{
-    cipher::{block_padding::Pkcs7, BlockEncryptMut, KeyIvInit}, Encryptor
+    }, http::StatusCode, response::{
+        sse::{Event, KeepAlive}, Response, Sse
+    }
 };
-use futures::FutureExt;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use chrono::TimeDelta;
+use futures::{FutureExt, Stream};
 use serde_json::json;
-use std::{future::Future, io, pin::Pin, time::Duration};
-use tokio::{sync::mpsc::Receiver, time::timeout};
-use tokio_util::{
-    bytes::{BufMut, BytesMut}, codec::Encoder
+use sqlx::PgPool;
+use std::{convert::Infallible, future::Future, pin::Pin, time::Duration};
+use tokio::{
+    sync::{
+        mpsc, mpsc::{Receiver, WeakSender}, oneshot
+    }, task, time::{interval, MissedTickBehavior}
 };
+use tokio_util::{bytes::BytesMut, codec::Encoder};
+use tracing::warn;
 use uuid::Uuid;
 
-#[allow(clippy::unused_async)]
 pub async fn ws_route(
     FallibleExtractor(ws): FallibleExtractor<WebSocketUpgrade>,
     owner: User,
-    State(AppState { tx, .. }): State<AppState>
+    State(AppState { pool, tx, .. }): State<AppState>
 ) -> Response {
+    let lineage = resolve_lineage(&pool, &owner).await;
+
     ws.on_upgrade(move |socket| async move {
-        let _ = tx.send(WebsocketActorMessage::Socket { socket, owner }).await;
+        let connection = Connection::new(Sink::Socket(Box::new(socket)), owner, lineage);
+        let _ = tx.send(WebsocketActorMessage::Socket(Box::new(connection))).await;
     })
 }
 
+/// Fallback for networks that refuse websocket upgrades, carrying the same frames base64 encoded.
+pub async fn sse_route(
+    owner: User,
+    State(AppState { pool, tx, .. }): State<AppState>
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let lineage = resolve_lineage(&pool, &owner).await;
+
+    let (events_tx, events_rx) = mpsc::channel(32);
+
+    let connection = Connection::new(Sink::Events(events_tx), owner, lineage);
+    let _ = tx.send(WebsocketActorMessage::Socket(Box::new(connection))).await;
+
+    let stream = futures::stream::unfold(events_rx, |mut events_rx| async move {
+        let event = match events_rx.recv().await? {
+            Message::Text(text) => Event::default().data(text.as_str()),
+            Message::Binary(body) => Event::default().data(BASE64_STANDARD.encode(body)),
+            _ => Event::default().comment("")
+        };
+
+        Some((Ok(event), events_rx))
+    });
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+/// The upstream half of an event stream: `[connection id 16]` followed by a frame as it would be sent over a socket.
+pub async fn sse_frame_route(
+    owner: User,
+    State(AppState { tx, .. }): State<AppState>,
+    body: Bytes
+) -> StatusCode {
+    let Some(connection) = body.get(..16).and_then(|id| Uuid::from_slice(id).ok()) else {
+        return StatusCode::BAD_REQUEST;
+    };
+
+    let frame = body.slice(16..);
+    let _ = tx.send(WebsocketActorMessage::Frame { connection, owner: owner.id, frame }).await;
+
+    StatusCode::NO_CONTENT
+}
+
+async fn resolve_lineage(pool: &PgPool, owner: &User) -> Lineage {
+    owner.lineage(pool).await.unwrap_or_else(|_| Lineage {
+        branch: owner.id,
+        location: owner.location_referral.clone(),
+        board: GLOBAL_BOARD
+    })
+}
+
+/// Where frames for a connection end up, either a live websocket or the channel feeding an event stream.
+pub enum Sink {
+    Socket(Box<WebSocket>),
+    Events(mpsc::Sender<Message>)
+}
+
+impl Sink {
+    async fn send(&mut self, message: Message) -> Result<(), axum::Error> {
+        match self {
+            Self::Socket(socket) => socket.send(message).await,
+            // never waits on a slow reader, the actor serves everyone else too. a stream that has
+            // fallen this far behind is dropped like a closed one and the client reconnects
+            Self::Events(events) => events.try_send(message).map_err(axum::Error::new)
+        }
+    }
+}
+
+pub struct Connection {
+    pub id: Uuid,
+    pub sink: Sink,
+    pub owner: User,
+    pub lineage: Lineage,
+    pub session: SessionState
+}
+
+impl Connection {
+    fn new(sink: Sink, owner: User, lineage: Lineage) -> Self {
+        Self { id: Uuid::new_v4(), sink, owner, lineage, session: SessionState::new() }
+    }
+
+    /// Seals `item` for this connection, or nothing until the key exchange has finished.
+    fn seal<T>(&mut self, item: T) -> Option<Message>
+    where
+        for<'a> MessageEncoder<'a>: Encoder<T>
+    {
+        let session = self.session.established()?;
+
+        let mut body = BytesMut::new();
+        MessageEncoder::new(session).encode(item, &mut body).ok()?;
+
+        Some(Message::Binary(body.freeze()))
+    }
+}
+
+/// Frames clients send up the socket, tagged by their first byte.
+enum ClientFrame {
+    Typing,
+    // [1][counter u64][ciphertext + tag] -> [request id u32][ttl choice u8][content]
+    Submit(Bytes),
+    // [2][client public 32]
+    Handshake([u8; 32]),
+    // [3][counter u64][ciphertext + tag] -> [request id u32][ttl choice u8][parent id 16][content]
+    Reply(Bytes),
+    // [4][counter u64][ciphertext + tag] -> [message id 16][emoji index u8]
+    React(Bytes)
+}
+
+impl ClientFrame {
+    fn parse(body: &Bytes) -> Option<Self> {
+        match body.first()? {
+            0 => Some(Self::Typing),
+            1 if body.len() <= MAX_SUBMISSION_BYTES => Some(Self::Submit(body.slice(1..))),
+            2 => Some(Self::Handshake(body.get(1..33)?.try_into().ok()?)),
+            3 if body.len() <= MAX_SUBMISSION_BYTES => Some(Self::Reply(body.slice(1..))),
+            4 if body.len() <= MAX_REACTION_BYTES => Some(Self::React(body.slice(1..))),
+            _ => None
+        }
+    }
+}
+
+/// A submission or reply once it has been opened.
+pub struct Submission {
+    request_id: u32,
+    parent: Option<Uuid>,
+    expires_in: Option<TimeDelta>,
+    crosspost: bool,
+    content: String
+}
+
+impl Submission {
+    /// Tampered, replayed or pre-handshake frames open to nothing.
+    fn open(connection: &mut Connection, kind: u8, sealed: &[u8]) -> Option<Self> {
+        let plaintext = connection.session.established()?.open(kind, sealed)?;
+        Self::parse(kind, &plaintext)
+    }
+
+    /// Reads an opened submission (kind 1) or reply (kind 3).
+    pub fn parse(kind: u8, plaintext: &[u8]) -> Option<Self> {
+        let (request_id, rest) = plaintext.split_first_chunk::<4>()?;
+        let ([ttl], rest) = rest.split_first_chunk::<1>()?;
+
+        // replies carry the id of the message they answer and go wherever it went,
+        // top level messages say whether to crosspost instead
+        let (parent, crosspost, content) = if kind == 3 {
+            let (parent, content) = rest.split_first_chunk::<16>()?;
+            (Some(Uuid::from_bytes(*parent)), false, content)
+        } else {
+            let ([crosspost], content) = rest.split_first_chunk::<1>()?;
+            (None, *crosspost != 0, content)
+        };
+
+        Some(Self {
+            request_id: u32::from_be_bytes(*request_id),
+            parent,
+            expires_in: *TTL_CHOICES.get(usize::from(*ttl))?,
+            crosspost,
+            content: String::from_utf8(content.to_vec()).ok()?
+        })
+    }
+}
+
+// roomy enough for the longest allowed message in multibyte text once sealed
+pub const MAX_SUBMISSION_BYTES: usize = 4096;
+// a message id and an emoji index once sealed, with room to spare
+const MAX_REACTION_BYTES: usize = 64;
+// how often sockets are checked for incoming frames and closure
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+// stops one chatty client from stalling the actor while its frames are drained
+const MAX_FRAMES_PER_POLL: usize = 16;
+
 pub enum WebsocketActorMessage {
-    Socket { socket: WebSocket, owner: User },
+    Socket(Box<Connection>),
+    Frame { connection: Uuid, owner: Uuid, frame: Bytes },
     Message { message: FullMessage, is_update: bool },
-    RequestCount { id: Uuid }
+    Ack { connection: Uuid, request_id: u32, outcome: PostOutcome },
+    Reaction(ReactionCount),
+    Announcement(Announcement),
+    AnnouncementRemoved(Uuid),
+    Expired { deleted: Vec<Uuid>, unpublished: Vec<Uuid> },
+    UserDeleted { user: Uuid, messages: Vec<Uuid>, reactions: Vec<ReactionCount> },
+    RequestCount { id: Uuid },
+    RequestPresence { reply: oneshot::Sender<PresenceReport> },
+    // header submissions are opened with the session of the connection that made them
+    EnvelopeKey { connection: Uuid, owner: Uuid, reply: oneshot::Sender<Aes256Gcm> },
+    // a page of history sealed for the connection that asked, the reply says it went out
+    History {
+        connection: Uuid,
+        owner: Uuid,
+        history: History<StandardMessage>,
+        reply: oneshot::Sender<()>
+    },
+    // answered straight away, so a reply means the actor is alive and keeping up
+    Ping { reply: oneshot::Sender<()> }
 }
 
 type SendMessageFuture<'a, E = axum::Error> =
@@ -38,168 +238,481 @@ type SendMessageFuture<'a, E = axum::Error> =
 
 type BroadcastSendMessageFuture<'a> = SendMessageFuture<'a, (axum::Error, Uuid)>;
 
-pub async fn socket_owner_actor(mut rx: Receiver<WebsocketActorMessage>) {
-    let mut sockets: Vec<(WebSocket, User)> = Vec::new();
+struct Hub {
+    sockets: Vec<Connection>,
+    presence: Presence,
+    typing: Typing,
+
+    pool: PgPool,
+    tx: WeakSender<WebsocketActorMessage>
+}
+
+pub async fn socket_owner_actor(
+    mut rx: Receiver<WebsocketActorMessage>,
+    pool: PgPool,
+    tx: WeakSender<WebsocketActorMessage>
+) {
+    let mut hub = Hub {
+        sockets: Vec::new(),
+        presence: Presence::default(),
+        typing: Typing::default(),
+        pool,
+        tx
+    };
+
+    let mut poll_interval = interval(POLL_INTERVAL);
+    poll_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
 
     loop {
-        let message_or_timeout = timeout(Duration::from_secs(1), rx.recv()).await;
-        let Ok(msg) = message_or_timeout else {
-            prune_dead_sockets(&mut sockets).await;
-            continue;
-        };
+        tokio::select! {
+            msg = rx.recv() => {
+                let Some(msg) = msg else { break };
+                hub.handle_actor_message(msg).await;
+            }
+            _ = poll_interval.tick() => {
+                for (connection, frame) in poll_sockets(&mut hub.sockets) {
+                    hub.handle_client_frame(connection, frame).await;
+                }
+
+                let boards = hub.typing.expire();
+                if !boards.is_empty() {
+                    push_typing(&mut hub.sockets, &hub.typing, &boards).await;
+                }
+            }
+        }
 
-        let Some(msg) = msg else { break };
+        sync_presence(&mut hub.sockets, &mut hub.presence).await;
+    }
+}
 
+impl Hub {
+    async fn handle_actor_message(&mut self, msg: WebsocketActorMessage) {
         match msg {
-            WebsocketActorMessage::Socket { socket, owner } => sockets.push((socket, owner)),
+            WebsocketActorMessage::Socket(connection) => {
+                let mut connection = *connection;
+
+                if let Some(public) = connection.session.public_key() {
+                    let handshake = Handshake { public, connection: connection.id };
+                    let _ = connection.sink.send(Message::Binary(handshake.frame())).await;
+                }
+
+                // admins don't wait on the key exchange, their feed is plain json
+                let snapshot = PresenceSnapshot::from_connections(&self.sockets);
+                if let Some(ws_msg) = presence_message_for(&mut connection, &snapshot) {
+                    let _ = connection.sink.send(ws_msg).await;
+                }
+
+                self.sockets.push(connection);
+            }
+            WebsocketActorMessage::Frame { connection, owner, frame } => {
+                // event stream connections can only be driven by whoever opened them
+                if !self.sockets.iter().any(|c| c.id == connection && c.owner.id == owner) {
+                    return;
+                }
+
+                if let Some(frame) = ClientFrame::parse(&frame) {
+                    self.handle_client_frame(connection, frame).await;
+                }
+            }
             WebsocketActorMessage::Message { message, is_update } => {
-                broadcast(&mut sockets, &message, is_update).await;
+                if !is_update {
+                    if let Some(board) = self.typing.stop(&message.author) {
+                        push_typing(&mut self.sockets, &self.typing, &[board]).await;
+                    }
+                }
+
+                broadcast(&mut self.sockets, &message, is_update).await;
+            }
+            WebsocketActorMessage::Expired { deleted, unpublished } => {
+                for id in deleted {
+                    broadcast_removal(&mut self.sockets, id, true).await;
+                }
+
+                for id in unpublished {
+                    broadcast_removal(&mut self.sockets, id, false).await;
+                }
+            }
+            WebsocketActorMessage::UserDeleted { user, messages, reactions } => {
+                // whatever they still have open can't post as someone who no longer exists
+                self.sockets.retain(|connection| !connection.owner.id.eq(&user));
+                if let Some(board) = self.typing.stop(&user) {
+                    push_typing(&mut self.sockets, &self.typing, &[board]).await;
+                }
+
+                for id in messages {
+                    broadcast_removal(&mut self.sockets, id, true).await;
+                }
+                for reaction in &reactions {
+                    broadcast_reaction(&mut self.sockets, reaction).await;
+                }
+            }
+            WebsocketActorMessage::Reaction(reaction) => {
+                broadcast_reaction(&mut self.sockets, &reaction).await;
+            }
+            WebsocketActorMessage::Announcement(announcement) => {
+                broadcast_announcement(&mut self.sockets, &announcement).await;
+            }
+            WebsocketActorMessage::AnnouncementRemoved(id) => {
+                broadcast_removal(&mut self.sockets, id, true).await;
+            }
+            WebsocketActorMessage::Ack { connection, request_id, outcome } => {
+                let Some(connection) = self.sockets.iter_mut().find(|c| c.id == connection) else {
+                    return;
+                };
+
+                if let Some(ws_msg) = ack_message_for(connection, request_id, &outcome) {
+                    let _ = connection.sink.send(ws_msg).await;
+                }
             }
             WebsocketActorMessage::RequestCount { id } => {
-                let len = sockets.len();
-                let Some((socket, _)) = sockets.iter_mut().find(|(_, user)| user.id.eq(&id)) else {
-                    continue;
+                let snapshot = PresenceSnapshot::from_connections(&self.sockets);
+                let Some(connection) = self.sockets.iter_mut().find(|c| c.owner.id.eq(&id)) else {
+                    return;
                 };
 
-                let _ = socket
-                    .send(Message::Text(Utf8Bytes::from(json!({"count": len}).to_string())))
-                    .await;
+                if let Some(ws_msg) = presence_message_for(connection, &snapshot) {
+                    let _ = connection.sink.send(ws_msg).await;
+                }
+            }
+            WebsocketActorMessage::RequestPresence { reply } => {
+                let snapshot = PresenceSnapshot::from_connections(&self.sockets);
+                let _ = reply.send(self.presence.report(snapshot));
+            }
+            WebsocketActorMessage::EnvelopeKey { connection, owner, reply } => {
+                let session = self
+                    .sockets
+                    .iter_mut()
+                    .find(|c| c.id == connection && c.owner.id == owner)
+                    .and_then(|c| c.session.established());
+
+                if let Some(session) = session {
+                    let _ = reply.send(session.envelope_cipher());
+                }
+            }
+            WebsocketActorMessage::History { connection, owner, history, reply } => {
+                if self.send_history(connection, owner, &history).await {
+                    let _ = reply.send(());
+                }
+            }
+            WebsocketActorMessage::Ping { reply } => {
+                let _ = reply.send(());
             }
         }
     }
+
+    async fn send_history(
+        &mut self,
+        connection: Uuid,
+        owner: Uuid,
+        history: &History<StandardMessage>
+    ) -> bool {
+        let Some(connection) =
+            self.sockets.iter_mut().find(|c| c.id == connection && c.owner.id == owner)
+        else {
+            return false;
+        };
+
+        match connection.seal(history) {
+            Some(ws_msg) => connection.sink.send(ws_msg).await.is_ok(),
+            None => false
+        }
+    }
+
+    async fn handle_client_frame(&mut self, connection: Uuid, frame: ClientFrame) {
+        let snapshot = PresenceSnapshot::from_connections(&self.sockets);
+        let Some(connection) = self.sockets.iter_mut().find(|c| c.id == connection) else {
+            return;
+        };
+
+        match frame {
+            ClientFrame::Typing => {
+                let boards = self.typing.mark(connection.owner.id, connection.lineage.board);
+                if !boards.is_empty() {
+                    push_typing(&mut self.sockets, &self.typing, &boards).await;
+                }
+            }
+            ClientFrame::Handshake(client_public) => {
+                if !connection.session.establish(client_public) {
+                    return;
+                }
+
+                if let Some(ws_msg) = presence_message_for(connection, &snapshot) {
+                    let _ = connection.sink.send(ws_msg).await;
+                }
+            }
+            ClientFrame::Submit(sealed) => {
+                if let Some(submission) = Submission::open(connection, 1, &sealed) {
+                    let (id, owner, board) =
+                        (connection.id, connection.owner.clone(), connection.lineage.board);
+                    self.spawn_submission(id, owner, board, submission);
+                }
+            }
+            ClientFrame::Reply(sealed) => {
+                if let Some(submission) = Submission::open(connection, 3, &sealed) {
+                    let (id, owner, board) =
+                        (connection.id, connection.owner.clone(), connection.lineage.board);
+                    self.spawn_submission(id, owner, board, submission);
+                }
+            }
+            ClientFrame::React(sealed) => {
+                let Some(plaintext) =
+                    connection.session.established().and_then(|s| s.open(4, &sealed))
+                else {
+                    return;
+                };
+
+                let Some((message, &[emoji])) = plaintext.split_first_chunk::<16>() else {
+                    return;
+                };
+
+                #[allow(clippy::let_underscore_future)]
+                let _ = task::spawn(handle_reaction(
+                    PgPool::clone(&self.pool),
+                    self.tx.clone(),
+                    connection.owner.clone(),
+                    connection.lineage.board,
+                    Uuid::from_bytes(*message),
+                    emoji
+                ));
+            }
+        }
+    }
+
+    fn spawn_submission(&self, connection: Uuid, owner: User, board: Uuid, submission: Submission) {
+        #[allow(clippy::let_underscore_future)]
+        let _ = task::spawn(handle_submission(
+            PgPool::clone(&self.pool),
+            self.tx.clone(),
+            connection,
+            owner,
+            board,
+            submission
+        ));
+    }
+}
+
+pub async fn handle_submission(
+    pool: PgPool,
+    tx: WeakSender<WebsocketActorMessage>,
+    connection: Uuid,
+    owner: User,
+    board: Uuid,
+    Submission { request_id, parent, expires_in, crosspost, content }: Submission
+) {
+    let Some(tx) = tx.upgrade() else { return };
+
+    let new_message = NewMessage { content: &content, parent, expires_in, board, crosspost };
+    let outcome = post_message(&pool, &tx, &owner, new_message).await;
+
+    let _ = tx.send(WebsocketActorMessage::Ack { connection, request_id, outcome }).await;
+}
+
+async fn handle_reaction(
+    pool: PgPool,
+    tx: WeakSender<WebsocketActorMessage>,
+    owner: User,
+    board: Uuid,
+    message: Uuid,
+    emoji: u8
+) {
+    let Some(tx) = tx.upgrade() else { return };
+
+    match toggle_reaction(&pool, &owner, board, message, emoji).await {
+        Ok(Some(reaction)) => {
+            let _ = tx.send(WebsocketActorMessage::Reaction(reaction)).await;
+        }
+        Ok(None) => {}
+        Err(why) => warn!("failed to toggle reaction: {why:?}")
+    }
 }
 
-async fn broadcast(sockets: &mut Vec<(WebSocket, User)>, message: &FullMessage, is_update: bool) {
+async fn send_each<F>(sockets: &mut Vec<Connection>, mut build: F)
+where
+    F: FnMut(&mut Connection) -> Option<Message>
+{
     let send_futures: Vec<BroadcastSendMessageFuture> = sockets
         .iter_mut()
-        .filter(|(_, owner)| !owner.id.eq(&message.author))
-        .filter_map(|(socket, user)| -> Option<BroadcastSendMessageFuture> {
-            let mut message_enc = MessageEncoder::new(user.encryption_key());
-
-            let ws_msg = message.encode_message_for(&mut message_enc, user, is_update)?;
+        .filter_map(|connection| -> Option<BroadcastSendMessageFuture> {
+            let ws_msg = build(connection)?;
+            let id = connection.id;
 
-            let fut = async move { socket.send(ws_msg).await.map_err(|e| (e, user.id)) };
+            let fut = async move { connection.sink.send(ws_msg).await.map_err(|e| (e, id)) };
             Some(Box::pin(fut) as BroadcastSendMessageFuture)
         })
         .collect();
 
     futures::future::join_all(send_futures).await.into_iter().filter_map(Result::err).for_each(
         |(_e, id)| {
-            // remove any dead sockets
-            sockets.retain(|(_, user)| !user.id.eq(&id));
+            // remove any dead sockets, the owner's other tabs are fine
+            sockets.retain(|c| c.id != id);
         }
     );
 }
 
-async fn prune_dead_sockets(sockets: &mut Vec<(WebSocket, User)>) {
-    let before_len = sockets.len();
-
-    sockets.retain_mut(|(socket, _)| match socket.recv().now_or_never() {
-        // No immediate future response - PASS
-        // Immediate future response WITH content - PASS
-        None | Some(Some(_)) => true,
-        // Immediate future response WITH NONE, no content - FAIL
-        _ => false
-    });
-
-    // new count, update admins
-    let len = sockets.len();
-    if before_len == len {
-        return;
-    }
+async fn broadcast(sockets: &mut Vec<Connection>, message: &FullMessage, is_update: bool) {
+    send_each(sockets, |connection| {
+        if connection.owner.id.eq(&message.author) {
+            return None;
+        }
 
-    let send_futures = sockets
-        .iter_mut()
-        .filter(|(_, user)| user.admin)
-        .map(|(socket, _)| {
-            Box::pin(async move {
-                socket.send(Message::Text(Utf8Bytes::from(json!({"count": len}).to_string()))).await
-            }) as SendMessageFuture
-        })
-        .collect::<Vec<_>>();
+        // admins watch every board, everyone else only their own
+        if !connection.owner.admin && !message.shows_on(connection.lineage.board) {
+            return None;
+        }
 
-    let _ = futures::future::join_all(send_futures).await;
+        message.encode_message_for(connection, is_update)
+    })
+    .await;
 }
 
-struct MessageEncoder {
-    encryptor: Encryptor<aes::Aes128>,
-    iv: [u8; 16]
+/// Takes a message off everyone's board. Admins can still see unpublished messages, so they
+/// only hear about ones that are gone for good.
+async fn broadcast_removal(sockets: &mut Vec<Connection>, id: Uuid, deleted: bool) {
+    send_each(sockets, |connection| {
+        if !connection.owner.admin {
+            return connection.seal(&DeleteMessage(id));
+        }
+
+        deleted.then(|| Message::Text(Utf8Bytes::from(json!({ "deleted": id }).to_string())))
+    })
+    .await;
 }
 
-impl MessageEncoder {
-    fn new<K: Into<Vec<u8>>>(key: K) -> Self {
-        let key = key.into();
+async fn broadcast_reaction(sockets: &mut Vec<Connection>, reaction: &ReactionCount) {
+    send_each(sockets, |connection| {
+        if connection.owner.admin
+            || !reaction.shows_to(connection.owner.id, connection.lineage.board)
+        {
+            return None;
+        }
 
-        let iv = rand::random::<[u8; 16]>();
-        let encryptor = Encryptor::<aes::Aes128>::new((&key[..]).into(), iv.as_ref().into());
+        // a banned user still sees their own reaction, nobody else sees a tally move or not
+        let own = connection.owner.id.eq(&reaction.author);
+        if reaction.banned && !own {
+            return None;
+        }
 
-        Self { encryptor, iv }
-    }
+        let count = if own { reaction.author_count } else { reaction.count };
 
-    fn init(&self, dst: &mut BytesMut) {
-        dst.put(&self.iv[..]);
-    }
+        connection.seal(&ReactionTally { message: reaction.message, emoji: reaction.emoji, count })
+    })
+    .await;
+}
 
-    #[allow(clippy::cast_possible_truncation, clippy::needless_pass_by_value)]
-    fn put_encrypted<S: ToString>(&self, content: S, dst: &mut BytesMut) {
-        let ct =
-            self.encryptor.clone().encrypt_padded_vec_mut::<Pkcs7>(content.to_string().as_bytes());
+async fn broadcast_announcement(sockets: &mut Vec<Connection>, announcement: &Announcement) {
+    send_each(sockets, |connection| {
+        if connection.owner.admin {
+            let payload = json!({ "announcement": announcement }).to_string();
+            return Some(Message::Text(Utf8Bytes::from(payload)));
+        }
 
-        dst.put_u32(ct.len() as u32);
-        dst.extend_from_slice(&ct);
-    }
+        if !announcement.shows_on(connection.lineage.board) {
+            return None;
+        }
 
-    fn noise(dst: &mut BytesMut) {
-        dst.extend_from_slice(&rand::random::<[u8; 8]>());
-    }
+        connection.seal(announcement)
+    })
+    .await;
 }
 
-impl Encoder<&FullMessage> for MessageEncoder {
-    type Error = io::Error;
+/// Drains whatever clients have sent since the last poll, pruning any sockets that have closed.
+fn poll_sockets(sockets: &mut Vec<Connection>) -> Vec<(Uuid, ClientFrame)> {
+    let mut frames = Vec::new();
+
+    sockets.retain_mut(|Connection { id, sink, .. }| {
+        let socket = match sink {
+            Sink::Socket(socket) => socket,
+            // event streams are one way, all there is to check is whether the client went away
+            Sink::Events(events) => return !events.is_closed()
+        };
 
-    fn encode(&mut self, item: &FullMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        self.init(dst);
-        dst.put_u8(0);
+        for _ in 0..MAX_FRAMES_PER_POLL {
+            match socket.recv().now_or_never() {
+                // Immediate future response WITH content - PASS, keep draining
+                Some(Some(Ok(message))) => {
+                    if let Some(frame) = match message {
+                        Message::Binary(body) => ClientFrame::parse(&body),
+                        _ => None
+                    } {
+                        frames.push((*id, frame));
+                    }
+                }
+                // No immediate future response - PASS
+                None | Some(Some(Err(_))) => return true,
+                // Immediate future response WITH NONE, no content - FAIL
+                Some(None) => return false
+            }
+        }
 
-        Self::noise(dst);
+        true
+    });
 
-        self.put_encrypted(item.id, dst);
-        self.put_encrypted(&item.content, dst);
-        self.put_encrypted(item.created_at, dst);
-        self.put_encrypted(item.author, dst);
+    frames
+}
 
-        Self::noise(dst);
+async fn sync_presence(sockets: &mut Vec<Connection>, presence: &mut Presence) {
+    let snapshot = PresenceSnapshot::from_connections(sockets);
+    presence.record(&snapshot);
 
-        Ok(())
+    if !presence.should_push(&snapshot) {
+        return;
     }
+
+    send_each(sockets, |connection| presence_message_for(connection, &snapshot)).await;
 }
 
-struct DeleteMessage(Uuid);
-impl Encoder<&DeleteMessage> for MessageEncoder {
-    type Error = io::Error;
+/// Sends fresh typing counts to everyone on `boards`. Admins watch every board, so they hear
+/// about all of them.
+async fn push_typing(sockets: &mut Vec<Connection>, typing: &Typing, boards: &[Uuid]) {
+    send_each(sockets, |connection| {
+        if connection.owner.admin {
+            let count = typing.count_excluding(&connection.owner.id, None);
+            return Some(Message::Text(Utf8Bytes::from(json!({ "typing": count }).to_string())));
+        }
+
+        let board = connection.lineage.board;
+        if !boards.contains(&board) {
+            return None;
+        }
 
-    fn encode(&mut self, item: &DeleteMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        self.init(dst);
-        dst.put_u8(1);
+        connection.seal(&TypingCount(typing.count_excluding(&connection.owner.id, Some(board))))
+    })
+    .await;
+}
 
-        Self::noise(dst);
+fn ack_message_for(
+    connection: &mut Connection,
+    request_id: u32,
+    outcome: &PostOutcome
+) -> Option<Message> {
+    let ack = Ack::new(request_id, outcome);
 
-        self.put_encrypted(item.0, dst);
+    if connection.owner.admin {
+        return Some(Message::Text(Utf8Bytes::from(json!({ "ack": ack }).to_string())));
+    }
 
-        Self::noise(dst);
+    connection.seal(&ack)
+}
 
-        Ok(())
+fn presence_message_for(
+    connection: &mut Connection,
+    snapshot: &PresenceSnapshot
+) -> Option<Message> {
+    if connection.owner.admin {
+        return Some(Message::Text(Utf8Bytes::from(serde_json::to_string(snapshot).ok()?)));
     }
+
+    let count = PresenceCount {
+        online: snapshot.count,
+        nearby: snapshot.nearby(connection.lineage.location.as_ref())
+    };
+
+    connection.seal(&count)
 }
 
 impl FullMessage {
-    fn encode_message_for(
-        &self,
-        encoder: &mut MessageEncoder,
-        user: &User,
-        is_update: bool
-    ) -> Option<Message> {
-        if user.admin {
+    fn encode_message_for(&self, connection: &mut Connection, is_update: bool) -> Option<Message> {
+        if connection.owner.admin {
             return if is_update {
                 None
             } else {
@@ -207,15 +720,16 @@ impl FullMessage {
             };
         }
 
-        let mut body = BytesMut::new();
+        // held messages only reach admins, a new one is never announced and an unpublished one
+        // is taken down
+        if !self.published {
+            return if is_update { connection.seal(&DeleteMessage(self.id)) } else { None };
+        }
 
-        if is_update && !self.published {
-            encoder.encode(&DeleteMessage(self.id), &mut body)
+        if let Some(parent) = self.parent_id {
+            connection.seal(&Reply { message: self, parent })
         } else {
-            encoder.encode(self, &mut body)
+            connection.seal(self)
         }
-        .ok()?;
-
-        Some(Message::Binary(body.freeze()))
     }
 }